@@ -0,0 +1,66 @@
+//! Audit trail of send requests, one JSON line per request in a file rotated by size
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::json;
+
+/// A request as recorded in the audit log, which never includes its api key
+pub struct AuditEntry<'a> {
+    pub sender: &'a str,
+    pub to: &'a str,
+    pub size: usize,
+    pub status: u16,
+}
+
+/// Log file renamed to `PATH.1`, `PATH.2`… when it would grow beyond its maximum size
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog { path: path.to_path_buf(), max_size, keep, file, size })
+    }
+
+    /// Append an entry, rotating the files first if it doesn't fit
+    pub fn record(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let line = format!(
+            "{{\"time\": {}, \"sender\": {}, \"to\": {}, \"size\": {}, \"status\": {}}}\n",
+            json::quote(&httpdate::fmt_http_date(SystemTime::now())),
+            json::quote(entry.sender),
+            json::quote(entry.to),
+            entry.size,
+            entry.status,
+        );
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the rotated files, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Decoding of request bodies sent in the charsets still common in legacy clients
+
+/// Characters of windows-1252 for bytes 0x80 to 0x9F, where it differs from iso-8859-1
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// A supported charset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Utf8,
+    Ascii,
+    Latin1,
+    Windows1252,
+}
+
+impl Charset {
+    /// Find a charset from its name or one of its usual aliases, ignoring case
+    pub fn from_name(name: &str) -> Option<Charset> {
+        match name.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Charset::Utf8),
+            "us-ascii" | "ascii" => Some(Charset::Ascii),
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" => Some(Charset::Latin1),
+            "windows-1252" | "cp1252" => Some(Charset::Windows1252),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Charset::Utf8 => "UTF-8",
+            Charset::Ascii => "US-ASCII",
+            Charset::Latin1 => "ISO-8859-1",
+            Charset::Windows1252 => "windows-1252",
+        }
+    }
+
+    /// Decode bytes into a string, failing on bytes that are invalid in the charset
+    pub fn decode(self, bytes: Vec<u8>) -> Option<String> {
+        match self {
+            Charset::Utf8 => String::from_utf8(bytes).ok(),
+            Charset::Ascii if bytes.is_ascii() => String::from_utf8(bytes).ok(),
+            Charset::Ascii => None,
+            Charset::Latin1 => Some(bytes.into_iter().map(char::from).collect()),
+            Charset::Windows1252 => Some(
+                bytes
+                    .into_iter()
+                    .map(|byte| match byte {
+                        0x80..=0x9F => WINDOWS_1252_HIGH[byte as usize - 0x80],
+                        byte => char::from(byte),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
@@ -1,17 +1,70 @@
-use std::io::Cursor;
-use tiny_http::{Response, StatusCode};
+use std::io::{Cursor, ErrorKind};
+use tiny_http::{Header, Response, StatusCode};
 
 /// Error returned by the HTTP API
 #[allow(clippy::enum_variant_names)]
 pub enum Error {
     AddressError(lettre::address::AddressError),
+    InvalidMailbox(&'static str, String, lettre::address::AddressError),
+    InvalidJsonList(&'static str),
     LettreError(lettre::error::Error),
     SmtpError(lettre::transport::smtp::Error),
+    SendmailError(lettre::transport::sendmail::Error),
+    FileError(std::io::Error),
+    InvalidBodyFormat(String),
+    InvalidEncoding(&'static str, String),
+    UnsuitableEncoding(&'static str, &'static str),
+    InvalidNullSender(String),
+    NullSenderNotAutomated,
+    EmptyEnvelopeTo,
+    MissingTextAlternative,
+    EmptyBody,
+    DisallowedContent(&'static str),
+    InvalidDeliveryMode(String),
+    IndividualWithCopies,
+    InvalidCalendar(String),
+    InvalidAutoSubmitted(String),
+    InvalidPrecedence(String),
+    InvalidFeedbackId(String),
+    InvalidEnvelopeId(String),
+    EnvelopeIdWithoutSmtp,
+    HeaderTooLong(&'static str, usize),
+    RepeatedHeader(&'static str),
     MissingTo,
     MissingFrom,
     MissingSubject,
     MissingApiKey,
     Unauthorized(String),
+    DisabledSender(String),
+    ForbiddenSenderDomain(String),
+    ForbiddenReplyTo(String),
+    DisplayNameMismatch(String),
+    BlockedRecipient(String),
+    BlockedUserAgent(String),
+    UnknownRelay(String),
+    MethodNotAllowed,
+    BodyTooLarge(u64),
+    MessageTooLarge(u64),
+    RateLimited(u64),
+    ExceedsRateLimit(usize, usize),
+    BodyReadError(std::io::Error),
+    InvalidBodyCharset(&'static str),
+    UnsupportedCharset(String),
+    InvalidBounce(String),
+    SuppressedRecipient(String),
+    ReloadFailed(String),
+    SaveFailed(String),
+    ForbiddenRelay(String),
+    InvalidTestFailure(String),
+    Simulated(TestFailure),
+}
+
+/// Failure forced by the X-Test-Fail header in test mode
+pub enum TestFailure {
+    Unauthorized,
+    Smtp,
+    Timeout,
+    RateLimited,
 }
 
 impl From<lettre::address::AddressError> for Error {
@@ -32,27 +85,111 @@ impl From<lettre::transport::smtp::Error> for Error {
     }
 }
 
+impl From<lettre::transport::sendmail::Error> for Error {
+    fn from(err: lettre::transport::sendmail::Error) -> Error {
+        Error::SendmailError(err)
+    }
+}
+
 impl Error {
     pub fn description(&self) -> String {
         match self {
             Error::AddressError(err) => format!("Failed to parse address: {err}"),
+            Error::InvalidMailbox(header, value, err) => format!("Failed to parse '{header}' address `{value}`: {err}"),
+            Error::InvalidJsonList(header) => format!("Invalid JSON array of strings in '{header}' header"),
+            Error::InvalidBodyFormat(format) => format!("Unknown 'X-Body-Format' value: {format}"),
+            Error::InvalidEncoding(header, encoding) => format!("Unknown '{header}' value: {encoding}"),
+            Error::InvalidNullSender(value) => format!("Unknown 'X-Null-Sender' value: {value}"),
+            Error::NullSenderNotAutomated => String::from("'X-Null-Sender' requires an 'Auto-Submitted' value other than 'no'"),
+            Error::UnsuitableEncoding(part, encoding) => format!("The {part} part can't be encoded as {encoding}, use quoted-printable or base64"),
+            Error::EmptyEnvelopeTo => String::from("Empty 'X-Envelope-To' header"),
+            Error::MissingTextAlternative => String::from("HTML emails must also have a text part"),
+            Error::EmptyBody => String::from("Email body is empty"),
+            Error::DisallowedContent(kind) => format!("Emails can't contain {kind} content"),
+            Error::InvalidDeliveryMode(mode) => format!("Unknown 'X-Delivery-Mode' value: {mode}"),
+            Error::IndividualWithCopies => String::from("'X-Delivery-Mode: individual' can't be combined with 'Cc', 'Bcc' or 'X-Envelope-To'"),
+            Error::InvalidCalendar(err) => format!("Invalid calendar invitation: {err}"),
+            Error::InvalidAutoSubmitted(value) => format!("Unknown 'Auto-Submitted' value: {value}"),
+            Error::InvalidPrecedence(value) => format!("Unknown 'Precedence' value: {value}"),
+            Error::InvalidFeedbackId(value) => format!("Invalid 'Feedback-ID' value, expected up to four colon-separated identifiers: {value}"),
+            Error::InvalidEnvelopeId(value) => format!("Invalid 'X-Envelope-Id' value, expected up to 100 printable ASCII characters: {value}"),
+            Error::EnvelopeIdWithoutSmtp => String::from("'X-Envelope-Id' can only be sent over SMTP"),
+            Error::HeaderTooLong(header, limit) => format!("'{header}' header exceeds {limit} bytes"),
+            Error::RepeatedHeader(header) => format!("'{header}' header must only be set once"),
             Error::MissingTo => String::from("Missing 'To' header"),
             Error::MissingFrom => String::from("Missing 'From' header"),
             Error::MissingSubject => String::from("Missing 'Subject' header"),
-            Error::MissingApiKey => String::from("Missing 'ApiKey' header"),
+            Error::MissingApiKey => String::from("Missing 'Api-Key' or 'Authorization: Bearer' header"),
             Error::LettreError(err) => format!("Lettre error: {err}"),
             Error::SmtpError(err) => format!("SMTP error: {err}"),
+            Error::SendmailError(err) => format!("Sendmail error: {err}"),
+            Error::FileError(err) => format!("Failed to write email: {err}"),
             Error::Unauthorized(api_key) => format!("Unauthorized api key: {api_key}"),
+            Error::MethodNotAllowed => String::from("Method not allowed"),
+            Error::BodyTooLarge(limit) => format!("Request body exceeds {limit} bytes"),
+            Error::MessageTooLarge(limit) => format!("Email exceeds {limit} bytes once encoded"),
+            Error::RateLimited(retry_after) => format!("Global rate limit exceeded, retry in {retry_after} seconds"),
+            Error::ExceedsRateLimit(emails, limit) => format!("Request would send {emails} emails, more than the global rate limit of {limit} allows at once"),
+            Error::BodyReadError(err) => match err.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => String::from("Timed out reading request body"),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => {
+                    format!("Connection closed while reading request body: {err}")
+                }
+                ErrorKind::InvalidData | ErrorKind::InvalidInput => format!("Malformed request body: {err}"),
+                _ => format!("Failed to read request body: {err}"),
+            },
+            Error::InvalidBodyCharset(charset) => format!("Request body is not valid {charset}"),
+            Error::UnsupportedCharset(charset) => format!("Unsupported charset: {charset}"),
+            Error::InvalidBounce(err) => format!("Invalid bounce notification: {err}"),
+            Error::SuppressedRecipient(recipient) => format!("Recipient is suppressed: {recipient}"),
+            Error::ReloadFailed(err) => format!("Failed to reload: {err}"),
+            Error::SaveFailed(err) => format!("Failed to save suppression list: {err}"),
+            Error::DisabledSender(sender) => format!("Sender is disabled: {sender}"),
+            Error::ForbiddenSenderDomain(sender) => format!("Sender domain is not allowed: {sender}"),
+            Error::ForbiddenReplyTo(reply_to) => format!("Reply-To domain is not allowed: {reply_to}"),
+            Error::DisplayNameMismatch(from) => format!("Display name is not allowed for this sender: {from}"),
+            Error::BlockedRecipient(recipient) => format!("Recipient is not allowed: {recipient}"),
+            Error::BlockedUserAgent(user_agent) => format!("User agent is not allowed: {user_agent}"),
+            Error::UnknownRelay(relay) => format!("Unknown relay: {relay}"),
+            Error::ForbiddenRelay(relay) => format!("Sender is not allowed to use relay: {relay}"),
+            Error::InvalidTestFailure(value) => format!("Unknown 'X-Test-Fail' value: {value}"),
+            Error::Simulated(TestFailure::Unauthorized) => String::from("Simulated unauthorized api key"),
+            Error::Simulated(TestFailure::Smtp) => String::from("Simulated SMTP error"),
+            Error::Simulated(TestFailure::Timeout) => String::from("Simulated SMTP timeout"),
+            Error::Simulated(TestFailure::RateLimited) => String::from("Simulated rate limit"),
         }
     }
 
     pub fn status_code(&self) -> u16 {
         match self {
-            Error::AddressError(_) => 400,
-            Error::MissingTo | Error::MissingFrom | Error::MissingSubject => 400,
+            Error::AddressError(_) | Error::InvalidMailbox(..) | Error::InvalidJsonList(_) => 400,
+            Error::InvalidBodyFormat(_) | Error::EmptyEnvelopeTo | Error::MissingTextAlternative | Error::EmptyBody | Error::DisallowedContent(_) | Error::InvalidCalendar(_) | Error::InvalidAutoSubmitted(_) | Error::InvalidPrecedence(_) | Error::InvalidFeedbackId(_) => 400,
+            Error::InvalidEnvelopeId(_) | Error::EnvelopeIdWithoutSmtp => 400,
+            Error::HeaderTooLong(..) | Error::RepeatedHeader(_) | Error::MissingTo | Error::MissingFrom | Error::MissingSubject => 400,
+            Error::InvalidDeliveryMode(_) | Error::IndividualWithCopies => 400,
+            Error::InvalidEncoding(..) | Error::UnsuitableEncoding(..) | Error::InvalidNullSender(_) | Error::NullSenderNotAutomated => 400,
             Error::LettreError(_) => 500,
             Error::SmtpError(_) => 500,
+            Error::SendmailError(_) => 500,
+            Error::FileError(_) => 500,
             Error::Unauthorized(_) | Error::MissingApiKey => 401,
+            Error::DisabledSender(_) | Error::ForbiddenSenderDomain(_) | Error::ForbiddenReplyTo(_) | Error::DisplayNameMismatch(_) | Error::BlockedRecipient(_) => 403,
+            Error::UnknownRelay(_) => 400,
+            Error::MethodNotAllowed => 405,
+            Error::BodyTooLarge(_) | Error::MessageTooLarge(_) => 413,
+            Error::RateLimited(_) => 429,
+            Error::ExceedsRateLimit(..) => 400,
+            Error::BodyReadError(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => 408,
+            Error::BodyReadError(_) | Error::InvalidBodyCharset(_) | Error::InvalidBounce(_) => 400,
+            Error::UnsupportedCharset(_) => 415,
+            Error::SuppressedRecipient(_) | Error::BlockedUserAgent(_) => 403,
+            Error::ReloadFailed(_) | Error::SaveFailed(_) => 500,
+            Error::ForbiddenRelay(_) => 403,
+            Error::InvalidTestFailure(_) => 400,
+            Error::Simulated(TestFailure::Unauthorized) => 401,
+            Error::Simulated(TestFailure::Smtp) => 500,
+            Error::Simulated(TestFailure::Timeout) => 504,
+            Error::Simulated(TestFailure::RateLimited) => 429,
         }
     }
 }
@@ -61,7 +198,17 @@ impl From<Error> for Response<Cursor<String>> {
     fn from(val: Error) -> Self {
         let description = val.description();
         let description_len = description.len();
-        Response::new_empty(StatusCode(val.status_code()))
-            .with_data(Cursor::new(description), Some(description_len))
+        let response = Response::new_empty(StatusCode(val.status_code()))
+            .with_data(Cursor::new(description), Some(description_len));
+        // Simulated limits carry the header too, so clients can test how they honor it
+        let retry_after = match val {
+            Error::RateLimited(retry_after) => Some(retry_after),
+            Error::Simulated(TestFailure::RateLimited) => Some(1),
+            _ => None,
+        };
+        match retry_after {
+            Some(retry_after) => response.with_header(Header::from_bytes(&b"Retry-After"[..], retry_after.to_string().as_bytes()).unwrap()),
+            None => response,
+        }
     }
 }
@@ -0,0 +1,140 @@
+//! Minimal JSON support for the few places where the API speaks JSON
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Parse a JSON array of strings such as `["a@example.org", "Doe, John <john@example.org>"]`
+pub fn parse_string_array(input: &str) -> Option<Vec<String>> {
+    let mut parser = Parser { chars: input.chars().peekable() };
+    let values = parser.string_array()?;
+    parser.end()?;
+    Some(values)
+}
+
+/// Parse a flat JSON object with string values such as `{"type": "bounce", "email": "a@example.org"}`
+pub fn parse_string_object(input: &str) -> Option<Vec<(String, String)>> {
+    let mut parser = Parser { chars: input.chars().peekable() };
+    let fields = parser.string_object()?;
+    parser.end()?;
+    Some(fields)
+}
+
+/// Quote a string as a JSON string literal
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Option<()> {
+        self.skip_whitespace();
+        self.chars.next_if_eq(&expected).map(|_| ())
+    }
+
+    fn end(&mut self) -> Option<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(_) => None,
+            None => Some(()),
+        }
+    }
+
+    fn string_array(&mut self) -> Option<Vec<String>> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        if self.expect(']').is_some() {
+            return Some(values);
+        }
+        loop {
+            values.push(self.string()?);
+            if self.expect(',').is_none() {
+                self.expect(']')?;
+                return Some(values);
+            }
+        }
+    }
+
+    fn string_object(&mut self) -> Option<Vec<(String, String)>> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        if self.expect('}').is_some() {
+            return Some(fields);
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.string()?));
+            if self.expect(',').is_none() {
+                self.expect('}')?;
+                return Some(fields);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(value),
+                '\\' => match self.chars.next()? {
+                    '"' => value.push('"'),
+                    '\\' => value.push('\\'),
+                    '/' => value.push('/'),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'u' => value.push(self.unicode_escape()?),
+                    _ => return None,
+                },
+                c if c.is_control() => return None,
+                c => value.push(c),
+            }
+        }
+    }
+
+    /// Parse the digits of a `\uXXXX` escape, combining surrogate pairs
+    fn unicode_escape(&mut self) -> Option<char> {
+        let high = self.hex4()?;
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high);
+        }
+        self.chars.next_if_eq(&'\\')?;
+        self.chars.next_if_eq(&'u')?;
+        let low = self.hex4()?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            code = code * 16 + self.chars.next()?.to_digit(16)?;
+        }
+        Some(code)
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::Path;
+use lettre::message::header::HeaderName;
+use lettre::Address;
+
+/// Headers that are set from the request and can't be defaulted per sender
+const RESERVED_HEADERS: &[&str] = &["from", "to", "cc", "bcc", "reply-to", "subject", "date", "mime-version", "content-type", "content-transfer-encoding"];
+
+/// An api key and the options of its sender
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub email: String,
+    pub hash: String,
+    /// Headers added to every email from this sender, unless the request sets them
+    pub headers: Vec<(String, String)>,
+    /// Only display name allowed in the From header of this sender
+    pub display_name: Option<String>,
+    /// Address shown in the From header instead of this sender's, when it is an alias
+    pub canonical_from: Option<Address>,
+}
+
+impl From<(String, String)> for ApiKey {
+    fn from((email, hash): (String, String)) -> ApiKey {
+        ApiKey { email, hash, headers: Vec::new(), display_name: None, canonical_from: None }
+    }
+}
+
+/// What to do with a From display name that isn't the one allowed for the sender
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayNameMismatch {
+    /// Reject the email
+    Reject,
+    /// Replace the display name with the allowed one
+    Rewrite,
+}
+
+/// Which address the envelope of an alias uses once its From header is rewritten to the canonical address
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanonicalEnvelope {
+    /// Keep the alias address, so bounces go to it
+    Original,
+    /// Use the canonical address
+    Canonical,
+}
+
+pub fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    let pos = s.find('=').ok_or_else(|| format!("invalid user=KEY: no `=` found in `{s}`"))?;
+    let key = s[..pos].parse::<String>().map_err(|e| format!("invalid key: {e}"))?.trim().to_lowercase();
+    // Keys are matched against the address of the From header, so anything else could never match
+    if let Err(e) = key.parse::<Address>() {
+        return Err(format!("invalid key: `{key}` is not a valid email address: {e}"));
+    }
+    let value = parse_hash(&s[pos + 1..]).map_err(|e| format!("invalid value: {e}"))?;
+    Ok((key, value))
+}
+
+/// Check a value is a sha256 hash in hexadecimal
+pub fn parse_hash(s: &str) -> Result<String, String> {
+    let value = s.trim().to_lowercase();
+    if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("`{value}` is not a sha256 hash"));
+    }
+    Ok(value)
+}
+
+/// Parse a line of the keys file: EMAIL=API_KEY followed by `; name=value` options
+///
+/// Options are `header=NAME: VALUE`, which can be repeated, `display-name=NAME` and `canonical-from=EMAIL`.
+fn parse_key_line(line: &str) -> Result<ApiKey, String> {
+    let mut parts = line.split(';');
+    let mut api_key = ApiKey::from(parse_key_val(parts.next().unwrap_or_default())?);
+    for option in parts.map(str::trim).filter(|option| !option.is_empty()) {
+        let (name, value) = option.split_once('=').ok_or_else(|| format!("invalid option: no `=` found in `{option}`"))?;
+        match name.trim() {
+            "header" => {
+                let (name, value) = value.split_once(':').ok_or_else(|| format!("invalid header: no `:` found in `{value}`"))?;
+                let name = name.trim();
+                HeaderName::new_from_ascii(name.to_string()).map_err(|_| format!("invalid header name: `{name}`"))?;
+                if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                    return Err(format!("invalid header: `{name}` can't have a default value"));
+                }
+                api_key.headers.push((name.to_string(), value.trim().to_string()));
+            }
+            "display-name" => {
+                let value = value.trim();
+                if value.is_empty() {
+                    return Err(String::from("invalid display name: empty"));
+                }
+                api_key.display_name = Some(value.to_string());
+            }
+            "canonical-from" => {
+                let value = value.trim();
+                let address = value.parse::<Address>().map_err(|e| format!("invalid canonical from: `{value}` is not a valid email address: {e}"))?;
+                api_key.canonical_from = Some(address);
+            }
+            name => return Err(format!("unknown option `{name}`")),
+        }
+    }
+    Ok(api_key)
+}
+
+/// Load api keys from a file containing one EMAIL=API_KEY entry per line
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn load_keys_file(path: &Path) -> Result<Vec<ApiKey>, String> {
+    read_lines(path)?
+        .into_iter()
+        .map(|(idx, line)| parse_key_line(&line).map_err(|e| format!("{}:{idx}: {e}", path.display())))
+        .collect()
+}
+
+/// Load a file containing one entry per line
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn load_list_file(path: &Path) -> Result<Vec<String>, String> {
+    Ok(read_lines(path)?.into_iter().map(|(_, line)| line).collect())
+}
+
+/// Read the meaningful lines of a file along with their line numbers
+fn read_lines(path: &Path) -> Result<Vec<(usize, String)>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    Ok(content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| (idx, line.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sha256 of "password"
+    const PASSWORD_HASH: &str = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+
+    #[test]
+    fn keys_must_be_email_addresses() {
+        let entry = |user: &str| parse_key_val(&format!("{user}={PASSWORD_HASH}"));
+        assert_eq!(entry(" Alice@Example.org "), Ok((String::from("alice@example.org"), String::from(PASSWORD_HASH))));
+        for user in ["foo@", "@x", "a b@x", "alice"] {
+            assert!(entry(user).is_err(), "{user}");
+        }
+    }
+}
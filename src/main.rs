@@ -1,12 +1,33 @@
-use std::io::Cursor;
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read};
 use sha2::{Sha256, Digest};
 use lettre::Message;
-use tiny_http::{Server, Response, Header, StatusCode};
-use lettre::{message::MultiPart, SmtpTransport, Transport, message::Mailbox};
-use clap::Parser;
+use std::num::NonZeroUsize;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use socket2::{Domain, Protocol, Socket, Type};
+use tiny_http::{Server, Request, Response, Header, Method, StatusCode};
+use lettre::{address::Envelope, message::header::{self, ContentTransferEncoding, ContentType, HeaderName, HeaderValue}, message::Body, message::MultiPart, message::SinglePart, message::Mailbox, message::Mailboxes, Address};
+use clap::{error::ErrorKind, CommandFactory, Parser};
 
+mod audit;
+mod charset;
 mod error;
-use error::Error;
+mod json;
+mod keys;
+mod metrics;
+mod rate_limit;
+mod relay;
+mod transport;
+use audit::{AuditEntry, AuditLog};
+use charset::Charset;
+use error::{Error, TestFailure};
+use keys::{load_keys_file, ApiKey, CanonicalEnvelope, DisplayNameMismatch, load_list_file, parse_hash, parse_key_val};
+use metrics::{AuthFailure, Metrics};
+use rate_limit::{parse_rate, parse_warmup, Rate, TokenBucket, Warmup};
+use relay::{parse_relay, Relay};
+use transport::{load_credentials_file, Mailer, TransportKind};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -15,148 +36,1563 @@ struct Cli {
    #[clap(short, long, value_parser, default_value = "localhost:8000")]
    addr: String,
 
+    /// Header added to every HTTP response, in the form NAME=VALUE, except Connection, Transfer-Encoding, Trailer, Upgrade, Content-Length and Content-Type
+    #[clap(long = "response-header", value_parser = parse_response_header, number_of_values = 1, value_name = "NAME=VALUE")]
+    response_headers: Vec<Header>,
+
+    /// Length of the queue of pending connections, instead of the system default
+    #[clap(long, value_name = "COUNT", value_parser = clap::value_parser!(i32).range(1..))]
+    backlog: Option<i32>,
+
+    /// Prefix of all endpoints, when served under a subpath (e.g. /mailer)
+    #[clap(long, value_parser = parse_path_prefix, value_name = "PATH", default_value = "")]
+    path_prefix: String,
+
+    /// Response to requests for the root path
+    #[clap(long, value_enum, value_name = "MODE", default_value = "redirect")]
+    root_mode: RootMode,
+
     /// Api keys in the form EMAIL=API_KEY where API_KEY is a sha256 hash
     #[clap(short = 'k', long = "api-key", value_parser = parse_key_val, number_of_values = 1, value_name = "EMAIL=API_KEY")]
     kv: Vec<(String, String)>,
+
+    /// What to do when the From display name isn't the one set with `display-name=` in the keys file
+    #[clap(long, value_enum, value_name = "ACTION", default_value = "reject")]
+    display_name_mismatch: DisplayNameMismatch,
+
+    /// Envelope sender of aliases whose From is rewritten with `canonical-from=` in the keys file
+    #[clap(long, value_enum, value_name = "MODE", default_value = "original")]
+    canonical_from_envelope: CanonicalEnvelope,
+
+    /// Reject requests whose User-Agent contains this text, ignoring case, before even checking their api key
+    #[clap(long = "blocked-user-agent", number_of_values = 1, value_name = "TEXT")]
+    blocked_user_agents: Vec<String>,
+
+    /// Senders whose emails are rejected even with a valid api key
+    #[clap(long = "disabled-sender", number_of_values = 1, value_name = "EMAIL")]
+    disabled_senders: Vec<String>,
+
+    /// Domains senders must belong to, directly or as a subdomain, whatever their api key
+    #[clap(long = "allowed-sender-domain", value_parser = parse_domain, number_of_values = 1, value_name = "DOMAIN")]
+    allowed_sender_domains: Vec<String>,
+
+    /// Domains Reply-To addresses must belong to, directly or as a subdomain
+    #[clap(long = "allowed-reply-to-domain", value_parser = parse_domain, number_of_values = 1, value_name = "DOMAIN")]
+    allowed_reply_to_domains: Vec<String>,
+
+    /// File containing one EMAIL=API_KEY entry per line, reloaded by POST /admin/reload
+    #[clap(long, value_name = "PATH")]
+    api_keys_file: Option<PathBuf>,
+
+    /// Sha256 hash of the api key allowed to use the /admin endpoints
+    #[clap(long, value_parser = parse_hash, value_name = "API_KEY")]
+    admin_key: Option<String>,
+
+    /// Let a reload remove every api key instead of keeping the previous ones
+    #[clap(long)]
+    allow_empty_reload: bool,
+
+    /// Sha256 hash of the api key allowed to post bounce and complaint notifications to /bounces
+    #[clap(long, value_parser = parse_hash, value_name = "API_KEY")]
+    bounces_key: Option<String>,
+
+    /// Stop sending to addresses reported on /bounces by adding them to the suppression list
+    #[clap(long)]
+    suppress_bounces: bool,
+
+    /// File keeping the suppression list across restarts, one address per line
+    #[clap(long, value_name = "PATH")]
+    suppression_file: Option<PathBuf>,
+
+    /// How emails are delivered when no relay is selected
+    #[clap(long, value_enum, default_value = "smtp")]
+    transport: TransportKind,
+
+    /// Path of the sendmail binary used by the sendmail transport
+    #[clap(long, value_name = "PATH", default_value = "/usr/sbin/sendmail")]
+    sendmail_path: PathBuf,
+
+    /// Directory where the file transport writes emails
+    #[clap(long, value_name = "PATH", required_if_eq("transport", "file"))]
+    mail_dir: Option<PathBuf>,
+
+    /// File containing the user=USER and password=PASSWORD used to authenticate to the local SMTP server
+    #[clap(long, value_name = "PATH")]
+    smtp_credentials_file: Option<PathBuf>,
+
+    /// Local IP address that SMTP connections to the local server and relays originate from
+    #[clap(long, value_name = "IP")]
+    smtp_bind_address: Option<IpAddr>,
+
+    /// Named SMTP relays in the form NAME=[USER:PASSWORD@]HOST[:PORT], selected with the X-Relay header, reached without TLS
+    #[clap(long = "relay", value_parser = parse_relay, number_of_values = 1, value_name = "NAME=RELAY")]
+    relays: Vec<(String, Relay)>,
+
+    /// Allow a sender to use a named relay, in the form EMAIL=NAME
+    #[clap(long = "relay-access", value_parser = parse_relay_access, number_of_values = 1, value_name = "EMAIL=NAME")]
+    relay_access: Vec<(String, String)>,
+
+    /// Status code returned when an email is sent, a 2xx one other than 204 and 205 which can't carry a report
+    #[clap(long, value_name = "CODE", default_value_t = 200, value_parser = parse_success_status)]
+    success_status: u16,
+
+    /// Maximum size of request bodies in bytes, chunked bodies included
+    #[clap(long, value_name = "BYTES", default_value_t = 25 * 1024 * 1024)]
+    max_body_size: u64,
+
+    /// Maximum length in bytes of the To, Cc, Bcc, From, Subject and Reply-To headers of requests
+    #[clap(long, value_name = "BYTES", default_value_t = 16 * 1024)]
+    max_header_length: usize,
+
+    /// Maximum size of emails in bytes once encoded, headers included
+    #[clap(long, value_name = "BYTES", default_value_t = 25 * 1024 * 1024)]
+    max_message_size: u64,
+
+    /// Maximum number of emails sent by all senders together, such as 500/min
+    #[clap(long, value_parser = parse_rate, value_name = "COUNT/UNIT")]
+    global_rate_limit: Option<Rate>,
+
+    /// Start the global rate limit lower for a new relay, such as 10/h:7d to grow from 10 emails per hour to the full limit over 7 days
+    #[clap(long, value_parser = parse_warmup, value_name = "COUNT/UNIT:PERIOD", requires = "global_rate_limit")]
+    warmup: Option<Warmup>,
+
+    /// Up to this many seconds added at random to Retry-After when rate limited, so clients don't all retry at once
+    #[clap(long, value_name = "SECONDS", default_value_t = 3)]
+    retry_after_jitter: u64,
+
+    /// Keep recipients listed several times in To, Cc and Bcc instead of only the most visible occurrence
+    #[clap(long)]
+    allow_duplicate_recipients: bool,
+
+    /// Whether local parts of recipient addresses are kept as given or lowercased, domains always being lowercased
+    #[clap(long, value_enum, value_name = "POLICY", default_value = "preserve")]
+    recipient_local_part: LocalPartCase,
+
+    /// Reject emails to role addresses such as postmaster@ or abuse@
+    #[clap(long)]
+    block_role_addresses: bool,
+
+    /// File containing one disposable domain per line to reject emails to, reloaded by POST /admin/reload
+    #[clap(long, value_name = "PATH")]
+    disposable_domains_file: Option<PathBuf>,
+
+    /// Address receiving a hidden copy of every email, for archiving
+    #[clap(long, value_name = "ADDRESS")]
+    archive_bcc: Option<Address>,
+
+    /// Address receiving a hidden copy of emails to a domain or its subdomains, in the form DOMAIN=ADDRESS
+    #[clap(long = "bcc-rule", value_parser = parse_bcc_rule, number_of_values = 1, value_name = "DOMAIN=ADDRESS")]
+    bcc_rules: Vec<(String, Address)>,
+
+    /// Maximum number of recipients per SMTP transaction, emails to more being sent once per chunk
+    #[clap(long, value_name = "COUNT")]
+    rcpt_chunk_size: Option<NonZeroUsize>,
+
+    /// Kinds of content emails may contain, all of them when not set
+    #[clap(long, value_enum, number_of_values = 1, value_name = "KIND")]
+    allowed_content: Vec<ContentKind>,
+
+    /// Reject HTML emails that don't also have a non-empty text part
+    #[clap(long)]
+    require_text_alternative: bool,
+
+    /// Reject emails whose body is empty or only whitespace, unless they have a calendar invitation
+    #[clap(long)]
+    reject_empty_body: bool,
+
+    /// Answer requests identical to one sent less than this many seconds ago without sending them again
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    dedupe_window: Option<u64>,
+
+    /// Mark outgoing emails as automated with `Auto-Submitted: auto-generated`
+    #[clap(long)]
+    auto_submitted: bool,
+
+    /// Value of the Precedence header added to outgoing emails, unless the request sets one
+    #[clap(long, value_parser = ["bulk", "list", "junk"], value_name = "VALUE")]
+    precedence: Option<String>,
+
+    /// Value of the Organization header added to outgoing emails, unless the request sets one
+    #[clap(long, value_parser = parse_header_value, value_name = "NAME")]
+    organization: Option<String>,
+
+    /// Value of the X-Mailer header added to outgoing emails
+    #[clap(long, value_parser = parse_header_value, value_name = "NAME")]
+    mailer_name: Option<String>,
+
+    /// Add X-Received-* headers telling the client IP, request ID and time of the request, which reveals infrastructure details
+    #[clap(long)]
+    diagnostic_headers: bool,
+
+    /// Check that the SMTP relays are reachable before listening, and exit otherwise
+    #[clap(long)]
+    verify_smtp_on_start: bool,
+
+    /// Send a test email to this address before listening, and exit if it fails
+    #[clap(long, value_name = "ADDRESS")]
+    self_test_to: Option<Address>,
+
+    /// Sender of the startup test email, the first api key's by default
+    #[clap(long, value_name = "ADDRESS", requires = "self_test_to")]
+    self_test_from: Option<Address>,
+
+    /// Serve counters in the Prometheus text format on GET /metrics, to clients with the metrics or admin key
+    #[clap(long)]
+    metrics: bool,
+
+    /// Sha256 hash of the api key allowed to read /metrics, the admin key being accepted when not set
+    #[clap(long, value_parser = parse_hash, value_name = "API_KEY", requires = "metrics")]
+    metrics_key: Option<String>,
+
+    /// Maximum number of distinct sender labels in metrics, others are counted as "other"
+    #[clap(long, value_name = "COUNT", default_value_t = 100)]
+    metrics_max_senders: usize,
+
+    /// File recording the sender, recipients, size and status of every send request, one JSON line each
+    #[clap(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Size in bytes beyond which the audit log is rotated
+    #[clap(long, value_name = "BYTES", default_value_t = 10 * 1024 * 1024, requires = "audit_log")]
+    audit_log_max_size: u64,
+
+    /// Number of rotated audit logs kept
+    #[clap(long, value_name = "COUNT", default_value_t = 5, requires = "audit_log")]
+    audit_log_keep: usize,
+
+    /// Accept requests without checking api keys, for local testing only
+    #[clap(long)]
+    allow_no_auth: bool,
+
+    /// Derive MIME boundaries from the body instead of picking them randomly, for golden-file tests
+    #[clap(long)]
+    deterministic_boundaries: bool,
+
+    /// Honor the X-Test-Fail header to simulate failures without sending
+    #[clap(long, hide = true)]
+    test_mode: bool,
+}
+
+fn parse_path_prefix(s: &str) -> Result<String, String> {
+    let prefix = s.trim().trim_matches('/');
+    if prefix.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!("/{prefix}"))
+}
+
+fn parse_success_status(s: &str) -> Result<u16, String> {
+    let status = s.trim().parse::<u16>().map_err(|e| format!("invalid status `{s}`: {e}"))?;
+    match status {
+        204 | 205 => Err(format!("{status} responses have no body, so the per-recipient report would be lost")),
+        200..=299 => Ok(status),
+        _ => Err(format!("{status} is not a 2xx status")),
+    }
+}
+
+fn parse_bcc_rule(s: &str) -> Result<(String, Address), String> {
+    let (domain, address) = s.split_once('=').ok_or_else(|| format!("invalid DOMAIN=ADDRESS: no `=` found in `{s}`"))?;
+    let domain = parse_domain(domain)?;
+    let address = address.trim().parse::<Address>().map_err(|e| format!("invalid address `{}`: {e}", address.trim()))?;
+    Ok((domain, address))
+}
+
+/// Headers tiny_http sets itself, or that would break the framing of responses
+const RESERVED_RESPONSE_HEADERS: [&str; 6] = ["Connection", "Transfer-Encoding", "Trailer", "Upgrade", "Content-Length", "Content-Type"];
+
+fn parse_response_header(s: &str) -> Result<Header, String> {
+    let (name, value) = s.split_once('=').ok_or_else(|| format!("invalid NAME=VALUE: no `=` found in `{s}`"))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+        return Err(format!("invalid header name: `{name}`"));
+    }
+    if let Some(reserved) = RESERVED_RESPONSE_HEADERS.iter().find(|reserved| reserved.eq_ignore_ascii_case(name)) {
+        return Err(format!("`{reserved}` is managed by the server and can't be set"));
+    }
+    let value = parse_header_value(value)?;
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).map_err(|_| format!("invalid header value: `{value}`"))
+}
+
+fn parse_domain(s: &str) -> Result<String, String> {
+    let domain = s.trim().trim_start_matches('@').to_lowercase();
+    if domain.is_empty() {
+        return Err(String::from("invalid domain: empty"));
+    }
+    Ok(domain)
+}
+
+fn parse_header_value(s: &str) -> Result<String, String> {
+    if s.contains(['\r', '\n']) {
+        return Err(String::from("header values can't contain line breaks"));
+    }
+    Ok(s.trim().to_string())
+}
+
+fn parse_relay_access(s: &str) -> Result<(String, String), String> {
+    let pos = s.find('=').ok_or_else(|| format!("invalid EMAIL=NAME: no `=` found in `{s}`"))?;
+    let email = s[..pos].trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(format!("invalid email: `{email}` is not an email"));
+    }
+    let name = s[pos + 1..].trim().to_lowercase();
+    Ok((email, name))
+}
+
+/// Server state that can be reloaded at runtime
+struct State {
+    api_keys: Vec<ApiKey>,
+    disposable_domains: HashSet<String>,
+    smtp_credentials: Option<(String, String)>,
+    suppressed: HashSet<String>,
 }
 
-fn parse_key_val(s: &str) -> Result<(String, String), String> {
-    let pos = s.find('=').ok_or_else(|| format!("invalid user=KEY: no `=` found in `{s}`"))?;
-    let key = s[..pos].parse::<String>().map_err(|e| format!("invalid key: {e}"))?.trim().to_lowercase();
-    // Quick check is email
-    if !key.contains('@') {
-        return Err(format!("invalid key: `{key}` is not an email"));
+impl State {
+    fn load(cli: &Cli) -> Result<State, String> {
+        let mut api_keys: Vec<ApiKey> = cli.kv.iter().cloned().map(ApiKey::from).collect();
+        if let Some(path) = &cli.api_keys_file {
+            api_keys.extend(load_keys_file(path)?);
+        }
+        let disposable_domains = match &cli.disposable_domains_file {
+            Some(path) => load_list_file(path)?.into_iter().map(|domain| domain.to_lowercase()).collect(),
+            None => HashSet::new(),
+        };
+        let smtp_credentials = cli.smtp_credentials_file.as_deref().map(load_credentials_file).transpose()?;
+        let suppressed = match &cli.suppression_file {
+            Some(path) if path.exists() => load_list_file(path)?.into_iter().map(|email| email.to_lowercase()).collect(),
+            _ => HashSet::new(),
+        };
+        Ok(State { api_keys, disposable_domains, smtp_credentials, suppressed })
     }
-    let value: String = s[pos + 1..].parse().map_err(|e| format!("invalid value: {e}"))?;
-    // Check is hash size
-    if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
-        return Err(format!("invalid value: `{value}` is not a sha256 hash"));
+
+    /// Write the suppression list to its file, if any
+    fn save_suppressions(&self, cli: &Cli) -> Result<(), Error> {
+        let Some(path) = &cli.suppression_file else {
+            return Ok(());
+        };
+        let mut suppressed = self.suppressed.iter().map(String::as_str).collect::<Vec<_>>();
+        suppressed.sort_unstable();
+        let content = suppressed.iter().map(|email| format!("{email}\n")).collect::<String>();
+
+        // Write to a temporary file first so a crash never leaves a truncated list
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|e| Error::SaveFailed(format!("failed to write {}: {e}", path.display())))
     }
-    Ok((key, value))
 }
 
+/// Headers whose values are limited by --max-header-length
+const BOUNDED_HEADERS: &[&str] = &["To", "Cc", "Bcc", "From", "Subject", "Reply-To"];
+
+/// Headers a request may only set once, so every check sees the value that is sent
+const SINGLE_HEADERS: &[&str] = &["To", "Cc", "Bcc", "From", "Subject", "Reply-To", "X-Envelope-To"];
+
+/// Local parts of addresses meant for administrators rather than people
+const ROLE_ADDRESSES: &[&str] = &["abuse", "admin", "hostmaster", "mailer-daemon", "noc", "postmaster", "root", "security", "webmaster"];
+
+/// How the message is delivered to its To recipients
+enum DeliveryMode {
+    /// A single message listing every recipient
+    Shared,
+    /// A copy per recipient, each seeing only themselves in To
+    Individual,
+}
+
+/// Case of the local parts of recipient addresses
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LocalPartCase {
+    /// Keep local parts exactly as given, for mailboxes that are case-sensitive
+    Preserve,
+    /// Lowercase local parts
+    Lowercase,
+}
+
+/// Response to requests for the root path
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum RootMode {
+    /// Redirect to the GitHub repository
+    Redirect,
+    /// JSON describing the service, its version and endpoints
+    Info,
+    /// Empty response
+    Empty,
+}
+
+/// Outcome of a request that sent at least one email
+#[derive(Clone)]
+struct Delivery {
+    sent: usize,
+    /// Recipients the email couldn't be sent to, the request succeeding for the others
+    failed: usize,
+    /// Per-recipient results, in individual delivery mode
+    report: Option<String>,
+    /// Envelope recipients, as parsed from the headers
+    recipients: Vec<Address>,
+}
+
+impl Delivery {
+    /// Status of the response, 207 Multi-Status telling clients that some recipients were missed
+    fn status(&self, cli: &Cli) -> u16 {
+        match self.failed {
+            0 => cli.success_status,
+            _ => 207,
+        }
+    }
+}
+
+/// How the request body is turned into the message body
+enum BodyFormat {
+    /// Multipart when the body contains the HTML delimiter, plain text otherwise
+    Auto,
+    Text,
+    Html,
+}
+
+/// Kind of content an email can contain
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ContentKind {
+    /// Plain text body or text part
+    Text,
+    /// HTML body or HTML part
+    Html,
+    /// Calendar invitation part
+    Calendar,
+}
+
+impl ContentKind {
+    fn name(self) -> &'static str {
+        match self {
+            ContentKind::Text => "text",
+            ContentKind::Html => "HTML",
+            ContentKind::Calendar => "calendar",
+        }
+    }
+}
+
+/// Delimiter before an iCalendar object ending the body, sent as an invitation
+const CALENDAR_DELIMITER: &str = "\n-----BEGIN-CALENDAR-----\n";
+
+/// Build the `text/calendar` part of an invitation, whose `method` parameter must match the METHOD property
+fn calendar_part(calendar: &str) -> Result<SinglePart, Error> {
+    let method = calendar
+        .lines()
+        .find_map(|line| line.trim_end().strip_prefix("METHOD:"))
+        .ok_or_else(|| Error::InvalidCalendar(String::from("missing METHOD property")))?;
+    if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(Error::InvalidCalendar(format!("invalid METHOD `{method}`")));
+    }
+    let content_type = ContentType::parse(&format!("text/calendar; method={}; charset=utf-8", method.to_ascii_uppercase()))
+        .map_err(|_| Error::InvalidCalendar(format!("invalid METHOD `{method}`")))?;
+    Ok(SinglePart::builder().header(content_type).body(calendar.to_string()))
+}
+
+/// Parse a list of mailboxes, reporting the first invalid entry
+///
+/// The list is either a JSON array of strings or comma-separated.
+/// A value that parses as a single mailbox is kept as-is, so display names containing commas still work.
+fn parse_mailboxes(header: &'static str, value: &str) -> Result<Vec<Mailbox>, Error> {
+    let entries = if value.trim_start().starts_with('[') {
+        json::parse_string_array(value).ok_or(Error::InvalidJsonList(header))?
+    } else if let Ok(mailbox) = value.parse::<Mailbox>() {
+        return Ok(vec![mailbox]);
+    } else {
+        value.split(',').map(|entry| entry.trim().to_string()).collect()
+    };
+    entries
+        .into_iter()
+        .map(|entry| parse_mailbox(entry.trim()).map_err(|err| Error::InvalidMailbox(header, entry, err)))
+        .collect()
+}
+
+/// Parse a single mailbox, also accepting display names that contain unquoted special characters
+fn parse_mailbox(entry: &str) -> Result<Mailbox, lettre::address::AddressError> {
+    entry.parse::<Mailbox>().or_else(|err| {
+        match (entry.rfind('<'), entry.strip_suffix('>')) {
+            (Some(idx), Some(entry)) => {
+                let name = entry[..idx].trim().trim_matches('"').trim();
+                let email = entry[idx + 1..].parse::<Address>()?;
+                Ok(Mailbox::new(Some(name.to_string()).filter(|name| !name.is_empty()), email))
+            }
+            _ => Err(err),
+        }
+    })
+}
+
+/// Lowercase the domains of addresses, and their local parts too unless they are preserved
+fn normalize_recipients<const N: usize>(lists: [&mut Vec<Mailbox>; N], case: LocalPartCase) -> Result<(), Error> {
+    for mailbox in lists.into_iter().flatten() {
+        let user = match case {
+            LocalPartCase::Preserve => mailbox.email.user().to_string(),
+            LocalPartCase::Lowercase => mailbox.email.user().to_lowercase(),
+        };
+        mailbox.email = Address::new(user, mailbox.email.domain().to_lowercase())?;
+    }
+    Ok(())
+}
+
+/// Check whether a domain is one of the given lowercase domains or one of their subdomains
+fn in_domains(domain: &str, domains: &[String]) -> bool {
+    let domain = domain.to_lowercase();
+    domains.iter().any(|allowed| &domain == allowed || domain.ends_with(&format!(".{allowed}")))
+}
+
+/// Keep only the first occurrence of each address, in the order of the lists given
+///
+/// Domains are compared ignoring case, local parts exactly.
+fn dedupe_recipients<const N: usize>(lists: [&mut Vec<Mailbox>; N]) {
+    let mut seen = HashSet::new();
+    for list in lists {
+        list.retain(|mailbox| seen.insert((mailbox.email.user().to_string(), mailbox.email.domain().to_lowercase())));
+    }
+}
+
+/// Get the lowercased address of a From header, ignoring any display name
+///
+/// Values that don't parse as a mailbox are lowercased as-is, so they simply match no key.
+fn sender_address(from: &str) -> String {
+    match parse_mailbox(from.trim()) {
+        Ok(mailbox) => mailbox.email.to_string().to_lowercase(),
+        Err(_) => from.trim().to_lowercase(),
+    }
+}
+
+/// Get the value of a header of a request, which must not repeat it when it is one of SINGLE_HEADERS
+fn header_value<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers.iter().find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name)).map(|header| header.value.as_str())
+}
+
+/// Reject requests repeating one of SINGLE_HEADERS, as different checks could otherwise pick different values
+fn check_repeated_headers(headers: &[Header]) -> Result<(), Error> {
+    for name in SINGLE_HEADERS {
+        if headers.iter().filter(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name)).count() > 1 {
+            return Err(Error::RepeatedHeader(name));
+        }
+    }
+    Ok(())
+}
+
+/// Hash compared against when the sender is unknown
+const DUMMY_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Compare two byte strings in time that only depends on their length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Check the api key of a request against the sender, using headers only
+///
+/// Returns the lowercased sender address.
+fn authenticate(headers: &[Header], cli: &Cli, state: &State, metrics: &mut Metrics) -> Result<String, Error> {
+    check_repeated_headers(headers)?;
+    let from = header_value(headers, "from").map(sender_address).unwrap_or_default();
+
+    // Check api key
+    match hashed_api_key(headers) {
+        _ if cli.allow_no_auth => (),
+        Some(hashed_api_key) => {
+            // Do the same work whether the sender is unknown or the key is wrong, so timing doesn't tell them apart
+            let mut expected = None;
+            for key in &state.api_keys {
+                if key.email == from && expected.is_none() {
+                    expected = Some(key.hash.as_str());
+                }
+            }
+            let matches = constant_time_eq(hashed_api_key.as_bytes(), expected.unwrap_or(DUMMY_HASH).as_bytes());
+            if expected.is_none() || !matches {
+                metrics.auth_failure(if expected.is_none() { AuthFailure::UnknownSender } else { AuthFailure::HashMismatch });
+                return Err(Error::Unauthorized(hashed_api_key));
+            }
+            metrics.auth_success();
+        }
+        None => {
+            metrics.auth_failure(AuthFailure::MissingKey);
+            return Err(Error::MissingApiKey);
+        }
+    }
+
+    // Check the sender isn't disabled
+    if cli.disabled_senders.iter().any(|sender| sender.eq_ignore_ascii_case(&from)) {
+        return Err(Error::DisabledSender(from));
+    }
+
+    Ok(from)
+}
+
+/// Headers of a request that don't change the email it sends or how, left out of dedupe keys
+const DEDUPE_IGNORED_HEADERS: [&str; 8] = ["api-key", "authorization", "host", "content-length", "transfer-encoding", "connection", "expect", "user-agent"];
+
+/// Hash the headers and body of a request, to recognize duplicates
+///
+/// Any header can end up in the email as a sender default, so all of them are hashed but credentials and framing ones.
+/// Headers are sorted by name so that their order doesn't matter, repeated ones keeping their relative order.
+fn dedupe_key(headers: &[Header], body: &str) -> String {
+    let mut fields: Vec<(String, &str)> = headers
+        .iter()
+        .map(|header| (header.field.as_str().as_str().to_ascii_lowercase(), header.value.as_str()))
+        .filter(|(name, _)| !DEDUPE_IGNORED_HEADERS.contains(&name.as_str()))
+        .collect();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = Sha256::new();
+    for (name, value) in fields {
+        hasher.update(name);
+        hasher.update([0]);
+        hasher.update(value);
+        hasher.update([0]);
+    }
+    hasher.update(body);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Get the api key of a request from its Api-Key header, or else from an `Authorization: Bearer` header
+fn api_key(headers: &[Header]) -> Option<&str> {
+    if let Some(header) = headers.iter().find(|header| header.field.equiv("api-key")) {
+        return Some(header.value.as_str());
+    }
+    let authorization = headers.iter().find(|header| header.field.equiv("authorization"))?.value.as_str().trim();
+    let (scheme, token) = authorization.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+/// Get the hash of the api key of a request
+fn hashed_api_key(headers: &[Header]) -> Option<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(api_key(headers)?);
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// Check the api key of a request against the single key of an endpoint
+fn check_endpoint_key(headers: &[Header], expected: Option<&str>) -> Result<(), Error> {
+    let hashed_api_key = hashed_api_key(headers).ok_or(Error::MissingApiKey)?;
+    if !constant_time_eq(hashed_api_key.as_bytes(), expected.unwrap_or(DUMMY_HASH).as_bytes()) {
+        return Err(Error::Unauthorized(hashed_api_key));
+    }
+    Ok(())
+}
+
+/// Reload api keys, lists and SMTP credentials, returning a summary of the changes
+///
+/// Transports are built for each email, so new credentials apply from the next one.
+fn reload(headers: &[Header], cli: &Cli, state: &mut State) -> Result<String, Error> {
+    check_endpoint_key(headers, cli.admin_key.as_deref())?;
+
+    // The previous state is kept unless the new one loads entirely
+    let mut new_state = State::load(cli).map_err(Error::ReloadFailed)?;
+    check_reloaded_state(state, &new_state, cli)?;
+    if cli.suppression_file.is_none() {
+        new_state.suppressed = std::mem::take(&mut state.suppressed);
+    }
+    let added = new_state.api_keys.iter().filter(|key| !state.api_keys.contains(key)).count();
+    let removed = state.api_keys.iter().filter(|key| !new_state.api_keys.contains(key)).count();
+    let credentials_changed = new_state.smtp_credentials != state.smtp_credentials;
+    *state = new_state;
+
+    let mut summary = format!("Reloaded api keys: {added} added, {removed} removed; {} disposable domains", state.disposable_domains.len());
+    if cli.smtp_credentials_file.is_some() {
+        summary.push_str(if credentials_changed { "; SMTP credentials changed" } else { "; SMTP credentials unchanged" });
+    }
+    println!("{summary}");
+    Ok(summary)
+}
+
+/// Check a reloaded state can replace the current one, which it can't by losing every api key unless allowed
+fn check_reloaded_state(state: &State, new_state: &State, cli: &Cli) -> Result<(), Error> {
+    if new_state.api_keys.is_empty() && !state.api_keys.is_empty() && !cli.allow_empty_reload {
+        return Err(Error::ReloadFailed(String::from("no api keys left, keeping the previous ones (see --allow-empty-reload)")));
+    }
+    Ok(())
+}
+
+/// Add (PUT) or remove (DELETE) an address from the suppression list, returning a summary
+fn update_suppression(headers: &[Header], method: &Method, email: &str, cli: &Cli, state: &mut State) -> Result<String, Error> {
+    check_endpoint_key(headers, cli.admin_key.as_deref())?;
+
+    let email = email.parse::<Address>()?.to_string().to_lowercase();
+    let summary = match method {
+        Method::Put if state.suppressed.insert(email.clone()) => format!("Suppressed {email}"),
+        Method::Put => format!("{email} was already suppressed"),
+        Method::Delete if state.suppressed.remove(&email) => format!("Unsuppressed {email}"),
+        Method::Delete => format!("{email} was not suppressed"),
+        _ => return Err(Error::MethodNotAllowed),
+    };
+    state.save_suppressions(cli)?;
+    println!("{summary}");
+    Ok(summary)
+}
+
+/// Record a bounce or complaint notification once authenticated, returning a summary
+///
+/// The body is a JSON object such as `{"type": "bounce", "email": "a@example.org"}`.
+fn bounce(body: &str, cli: &Cli, state: &mut State) -> Result<String, Error> {
+    let fields = json::parse_string_object(body).ok_or_else(|| Error::InvalidBounce(String::from("expected a JSON object of strings")))?;
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    let kind = match field("type") {
+        Some(kind @ ("bounce" | "complaint")) => kind,
+        Some(kind) => return Err(Error::InvalidBounce(format!("unknown type `{kind}`"))),
+        None => return Err(Error::InvalidBounce(String::from("missing `type`"))),
+    };
+    let email = field("email").ok_or_else(|| Error::InvalidBounce(String::from("missing `email`")))?;
+    let email = email.parse::<Address>()?.to_string().to_lowercase();
+
+    let summary = format!("Received a {kind} for {email}");
+    println!("{summary}");
+    if cli.suppress_bounces && state.suppressed.insert(email) {
+        state.save_suppressions(cli)?;
+    }
+    Ok(summary)
+}
+
+/// Read the body of a request in the charset of its Content-Type, UTF-8 by default, without ever buffering more than the limit
+///
+/// Reading sends `100 Continue` to clients that sent `Expect: 100-continue`, so rejections must happen before.
+fn read_body(request: &mut Request, max_body_size: u64) -> Result<String, Error> {
+    let charset = request.headers().iter().find(|header| header.field.equiv("content-type")).and_then(|header| {
+        header.value.as_str().split(';').skip(1).find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("charset").then(|| value.trim().to_string())
+        })
+    });
+    let charset = match charset {
+        Some(name) => Charset::from_name(&name).ok_or(Error::UnsupportedCharset(name))?,
+        None => Charset::Utf8,
+    };
+    if request.body_length().is_some_and(|len| len as u64 > max_body_size) {
+        return Err(Error::BodyTooLarge(max_body_size));
+    }
+    let mut body = Vec::new();
+    request.as_reader().take(max_body_size + 1).read_to_end(&mut body).map_err(Error::BodyReadError)?;
+    if body.len() as u64 > max_body_size {
+        return Err(Error::BodyTooLarge(max_body_size));
+    }
+    charset.decode(body).ok_or(Error::InvalidBodyCharset(charset.name()))
+}
+
+/// Handle a single HTTP request, once authenticated
+///
+/// With `dry_run`, nothing is sent and the report describes the message instead.
+fn handle_request(headers: &[Header], body: String, cli: &Cli, state: &State, remote_addr: Option<&SocketAddr>, dry_run: bool, rate_limit: Option<&mut TokenBucket>) -> Result<Delivery, Error> {
+    check_repeated_headers(headers)?;
 
-/// Handle a single HTTP request
-fn handle_request(headers: &[Header], body: String, hashed_api_keys: &[(String, String)]) -> Result<(), Error> {
     // Extract parameters
     let mut to = None;
+    let mut cc = None;
+    let mut bcc = None;
     let mut from = None;
     let mut subject = None;
     let mut reply_to = None;
-    let mut api_key = None;
+    let mut test_fail = None;
+    let mut relay = None;
+    let mut body_format = None;
+    let mut text_encoding = None;
+    let mut html_encoding = None;
+    let mut null_sender = None;
+    let mut envelope_to = None;
+    let mut delivery_mode = None;
+    let mut request_id = None;
+    let mut feedback_id = None;
+    let mut envelope_id = None;
+    let mut organization = cli.organization.clone();
+    let mut precedence = cli.precedence.clone();
+    let mut auto_submitted = cli.auto_submitted.then(|| String::from("auto-generated"));
     for header in headers {
+        if let Some(name) = BOUNDED_HEADERS.iter().find(|name| header.field.as_str().as_str().eq_ignore_ascii_case(name)) {
+            if header.value.as_str().len() > cli.max_header_length {
+                return Err(Error::HeaderTooLong(name, cli.max_header_length));
+            }
+        }
         match header.field.as_str().to_ascii_lowercase().as_str() {
             "to" => to = Some(header.value.to_string()),
-            "from" => from = Some(header.value.to_ascii_lowercase().to_string()),
+            "cc" => cc = Some(header.value.to_string()),
+            "bcc" => bcc = Some(header.value.to_string()),
+            "from" => from = Some(header.value.to_string()),
             "subject" => subject = Some(header.value.to_string()),
             "reply-to" => reply_to = Some(header.value.to_string()),
-            "api-key" => api_key = Some(header.value.to_string()),
+            "organization" => organization = Some(header.value.as_str().trim().to_string()),
+            "feedback-id" => feedback_id = Some(header.value.as_str().trim().to_string()),
+            "x-envelope-id" => envelope_id = Some(header.value.as_str().trim().to_string()),
+            "precedence" => precedence = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "auto-submitted" => auto_submitted = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "x-envelope-to" => envelope_to = Some(header.value.to_string()),
+            "x-null-sender" => null_sender = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "x-request-id" => request_id = Some(header.value.as_str().trim().to_string()),
+            "x-delivery-mode" => delivery_mode = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "x-body-format" => body_format = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "x-text-encoding" => text_encoding = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "x-html-encoding" => html_encoding = Some(header.value.as_str().trim().to_ascii_lowercase()),
+            "x-relay" => relay = Some(header.value.as_str().trim().to_lowercase()),
+            "x-test-fail" if cli.test_mode => test_fail = Some(header.value.to_string()),
             _ => {}
         }
     }
 
-    // Check api key
-    match api_key {
-        Some(api_key) => {
-            let mut hasher = Sha256::new();
-            hasher.update(&api_key);
-            let hashed_api_key = hasher.finalize();
-            let hashed_api_key = format!("{hashed_api_key:x}");
-            let from = from.as_ref().map(|f| f.to_ascii_lowercase()).unwrap_or_default();
-            let mut authorized = false;
-            for (user, hashed_key) in hashed_api_keys {
-                if user == &from {
-                    if !hashed_api_key.contains(hashed_key) {
-                        return Err(Error::Unauthorized(hashed_api_key));
-                    } else {
-                        authorized = true;
-                        break;
-                    }
-                }
-            }
-            if !authorized {
-                return Err(Error::Unauthorized(hashed_api_key));
+    // Simulate failures in test mode
+    if let Some(test_fail) = test_fail {
+        let failure = match test_fail.trim().to_ascii_lowercase().as_str() {
+            "unauthorized" => TestFailure::Unauthorized,
+            "smtp" => TestFailure::Smtp,
+            "timeout" => TestFailure::Timeout,
+            "ratelimited" => TestFailure::RateLimited,
+            _ => return Err(Error::InvalidTestFailure(test_fail)),
+        };
+        return Err(Error::Simulated(failure));
+    }
+
+    // Select the relay
+    let relay = match relay {
+        Some(relay) => {
+            let (_, selected) = cli.relays.iter().find(|(name, _)| name == &relay).ok_or_else(|| Error::UnknownRelay(relay.clone()))?;
+            let sender = sender_address(from.as_deref().unwrap_or_default());
+            if !cli.relay_access.iter().any(|(email, name)| email == &sender && name == &relay) {
+                return Err(Error::ForbiddenRelay(relay));
             }
+            Some(selected)
         }
-        None => return Err(Error::MissingApiKey),
-    }
+        None => None,
+    };
 
     // Parse and validate parameters
-    let to = to.map(|to| to.parse::<Mailbox>()).transpose()?.ok_or(Error::MissingTo)?;
-    let from = from.map(|from| from.parse::<Mailbox>()).transpose()?.ok_or(Error::MissingFrom)?;
-    let reply_to = reply_to.map(|reply_to| reply_to.parse::<Mailbox>()).transpose()?;
+    let mut to = to.map(|to| parse_mailboxes("To", &to)).transpose()?.filter(|to| !to.is_empty()).ok_or(Error::MissingTo)?;
+    let mut cc = cc.map(|cc| parse_mailboxes("Cc", &cc)).transpose()?.unwrap_or_default();
+    let mut bcc = bcc.map(|bcc| parse_mailboxes("Bcc", &bcc)).transpose()?.unwrap_or_default();
+    normalize_recipients([&mut to, &mut cc, &mut bcc], cli.recipient_local_part)?;
+    if !cli.allow_duplicate_recipients {
+        dedupe_recipients([&mut to, &mut cc, &mut bcc]);
+    }
+    let mut from = from.map(|from| parse_mailbox(from.trim())).transpose()?.ok_or(Error::MissingFrom)?;
+    let from_email = from.email.to_string().to_lowercase();
+    if !cli.allowed_sender_domains.is_empty() && !in_domains(from.email.domain(), &cli.allowed_sender_domains) {
+        return Err(Error::ForbiddenSenderDomain(from_email));
+    }
+    let api_key = state.api_keys.iter().find(|key| key.email == from_email);
+    let display_name = api_key.and_then(|key| key.display_name.as_ref());
+    if let Some(display_name) = display_name.filter(|display_name| from.name.as_ref() != Some(display_name)) {
+        match cli.display_name_mismatch {
+            DisplayNameMismatch::Reject => return Err(Error::DisplayNameMismatch(from.to_string())),
+            DisplayNameMismatch::Rewrite => from = Mailbox::new(Some(display_name.clone()), from.email),
+        }
+    }
+    // Present aliases with their canonical address, authentication having matched the alias
+    let mut envelope_from = from.email.clone();
+    if let Some(canonical_from) = api_key.and_then(|key| key.canonical_from.clone()) {
+        if cli.canonical_from_envelope == CanonicalEnvelope::Canonical {
+            envelope_from = canonical_from.clone();
+        }
+        from = Mailbox::new(from.name, canonical_from);
+    }
+    let reply_to = reply_to.map(|reply_to| parse_mailboxes("Reply-To", &reply_to)).transpose()?.unwrap_or_default();
+    if !cli.allowed_reply_to_domains.is_empty() {
+        if let Some(reply_to) = reply_to.iter().find(|reply_to| !in_domains(reply_to.email.domain(), &cli.allowed_reply_to_domains)) {
+            return Err(Error::ForbiddenReplyTo(reply_to.email.to_string()));
+        }
+    }
+    let mut envelope_to = envelope_to.map(|envelope_to| parse_mailboxes("X-Envelope-To", &envelope_to)).transpose()?;
+    if let Some(envelope_to) = &mut envelope_to {
+        normalize_recipients([envelope_to], cli.recipient_local_part)?;
+    }
     let subject = subject.ok_or(Error::MissingSubject)?;
+    if let Some(value) = &auto_submitted {
+        if !["no", "auto-generated", "auto-replied", "auto-notified"].contains(&value.as_str()) {
+            return Err(Error::InvalidAutoSubmitted(value.clone()));
+        }
+    }
+    if let Some(value) = &precedence {
+        if !["bulk", "list", "junk"].contains(&value.as_str()) {
+            return Err(Error::InvalidPrecedence(value.clone()));
+        }
+    }
+    if let Some(value) = &feedback_id {
+        // Up to four identifiers such as campaign:customer:type:sender
+        let identifiers: Vec<&str> = value.split(':').collect();
+        let valid_identifier = |id: &&str| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if identifiers.len() > 4 || !identifiers.iter().all(valid_identifier) {
+            return Err(Error::InvalidFeedbackId(value.clone()));
+        }
+    }
+    if let Some(value) = &envelope_id {
+        // Printable ASCII once xtext-decoded, up to 100 characters (RFC 3461)
+        if value.is_empty() || value.len() > 100 || !value.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(Error::InvalidEnvelopeId(value.clone()));
+        }
+        if relay.is_none() && cli.transport != TransportKind::Smtp {
+            return Err(Error::EnvelopeIdWithoutSmtp);
+        }
+    }
+    let delivery_mode = match delivery_mode.as_deref() {
+        None | Some("shared") => DeliveryMode::Shared,
+        Some("individual") if cc.is_empty() && bcc.is_empty() && envelope_to.is_none() => DeliveryMode::Individual,
+        Some("individual") => return Err(Error::IndividualWithCopies),
+        Some(_) => return Err(Error::InvalidDeliveryMode(delivery_mode.unwrap_or_default())),
+    };
+    let body_format = match body_format.as_deref() {
+        None | Some("auto") => BodyFormat::Auto,
+        Some("text") => BodyFormat::Text,
+        Some("html") => BodyFormat::Html,
+        Some(_) => return Err(Error::InvalidBodyFormat(body_format.unwrap_or_default())),
+    };
+    let null_sender = match null_sender.as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => return Err(Error::InvalidNullSender(null_sender.unwrap_or_default())),
+    };
+    // Only automated emails may avoid bounces, so people always learn about their emails not being delivered
+    if null_sender && auto_submitted.as_deref().unwrap_or("no") == "no" {
+        return Err(Error::NullSenderNotAutomated);
+    }
+    let text_encoding = parse_encoding("X-Text-Encoding", text_encoding)?;
+    let html_encoding = parse_encoding("X-Html-Encoding", html_encoding)?;
 
     // Build the message
     let mut email = Message::builder()
         .from(from.clone())
-        .to(to.clone())
         .subject(subject);
-    if let Some(reply_to) = reply_to {
+    for to in &to {
+        email = email.to(to.clone());
+    }
+    for cc in &cc {
+        email = email.cc(cc.clone());
+    }
+    for bcc in &bcc {
+        email = email.bcc(bcc.clone());
+    }
+    for reply_to in reply_to {
         email = email.reply_to(reply_to);
     }
-    let email = if let Some(idx) = body.find("\n-----END-TEXT-BEGIN-HTML-----\n") {
-        let body_text = &body[..idx];
-        let body_html = &body[idx + 31..];
-        email.multipart(MultiPart::alternative_plain_html(
-            String::from(body_text),
-            String::from(body_html),
-        ))?
-    } else {
-        email.body(body.clone())?
+    if envelope_to.is_some() || envelope_from != from.email || null_sender {
+        let recipients = match envelope_to {
+            Some(envelope_to) if envelope_to.is_empty() => return Err(Error::EmptyEnvelopeTo),
+            Some(envelope_to) => envelope_to.into_iter().map(|mailbox| mailbox.email).collect(),
+            None => to.iter().chain(&cc).chain(&bcc).map(|mailbox| mailbox.email.clone()).collect(),
+        };
+        email = email.envelope(Envelope::new(Some(envelope_from).filter(|_| !null_sender), recipients)?);
+    }
+    let (content, calendar) = match (&body_format, body.rfind(CALENDAR_DELIMITER)) {
+        (BodyFormat::Auto | BodyFormat::Html, Some(idx)) => (&body[..idx], Some(calendar_part(&body[idx + CALENDAR_DELIMITER.len()..])?)),
+        _ => (body.as_str(), None),
+    };
+    let delimiter = content.find("\n-----END-TEXT-BEGIN-HTML-----\n");
+    if cli.reject_empty_body && calendar.is_none() {
+        let is_empty = match (&body_format, delimiter) {
+            (BodyFormat::Auto, Some(idx)) => content[..idx].trim().is_empty() && content[idx + 31..].trim().is_empty(),
+            _ => content.trim().is_empty(),
+        };
+        if is_empty {
+            return Err(Error::EmptyBody);
+        }
+    }
+    if !cli.allowed_content.is_empty() {
+        let has_html = matches!(body_format, BodyFormat::Html) || matches!((&body_format, delimiter), (BodyFormat::Auto, Some(_)));
+        let present = [
+            (ContentKind::Text, !matches!(body_format, BodyFormat::Html)),
+            (ContentKind::Html, has_html),
+            (ContentKind::Calendar, calendar.is_some()),
+        ];
+        if let Some((kind, _)) = present.iter().find(|(kind, present)| *present && !cli.allowed_content.contains(kind)) {
+            return Err(Error::DisallowedContent(kind.name()));
+        }
+    }
+    // Derive the boundary from the body when asked to, so the same request always produces the same email
+    let alternative = || match cli.deterministic_boundaries {
+        true => MultiPart::alternative().boundary(format!("{:x}", Sha256::digest(body.as_bytes()))[..40].to_string()),
+        false => MultiPart::alternative(),
     };
+    let text = |content: &str| encode_body(content, text_encoding, "text");
+    let html = |content: &str| encode_body(content, html_encoding, "HTML");
+    let mut email = match (body_format, delimiter, calendar) {
+        (BodyFormat::Auto, Some(idx), calendar) => {
+            let body_text = &content[..idx];
+            if cli.require_text_alternative && body_text.trim().is_empty() {
+                return Err(Error::MissingTextAlternative);
+            }
+            let body_html = &content[idx + 31..];
+            let alternative = alternative()
+                .singlepart(SinglePart::plain(text(body_text)?))
+                .singlepart(SinglePart::html(html(body_html)?));
+            match calendar {
+                Some(calendar) => email.multipart(alternative.singlepart(calendar))?,
+                None => email.multipart(alternative)?,
+            }
+        }
+        (BodyFormat::Html, _, _) if cli.require_text_alternative => return Err(Error::MissingTextAlternative),
+        (BodyFormat::Html, _, Some(calendar)) => email.multipart(alternative().singlepart(SinglePart::html(html(content)?)).singlepart(calendar))?,
+        (BodyFormat::Html, _, None) => email.singlepart(SinglePart::html(html(&body)?))?,
+        (BodyFormat::Auto | BodyFormat::Text, _, Some(calendar)) => email.multipart(alternative().singlepart(SinglePart::plain(text(content)?)).singlepart(calendar))?,
+        (BodyFormat::Auto | BodyFormat::Text, _, None) => email.body(text(&body)?)?,
+    };
+    // Add default headers, sender ones taking precedence over global ones, unless the request sets them
+    let global_headers = organization.into_iter().map(|organization| (String::from("Organization"), organization));
+    let sender_headers = state.api_keys.iter().find(|key| key.email == from_email).map(|key| key.headers.clone()).unwrap_or_default();
+    let sender_sets = |name: &str| sender_headers.iter().any(|(sender_name, _)| sender_name.eq_ignore_ascii_case(name));
+    // Global flags only apply to headers the sender has no default for, unless the request sets them
+    let auto_submitted = auto_submitted.filter(|_| header_value(headers, "Auto-Submitted").is_some() || !sender_sets("Auto-Submitted"));
+    let precedence = precedence.filter(|_| header_value(headers, "Precedence").is_some() || !sender_sets("Precedence"));
+    let mailer_name = cli.mailer_name.as_ref().filter(|_| !sender_sets("X-Mailer"));
+    for (name, value) in global_headers.chain(sender_headers.iter().cloned()) {
+        let value = headers.iter().find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(&name)).map(|header| header.value.to_string()).unwrap_or(value);
+        if let Ok(name) = HeaderName::new_from_ascii(name) {
+            email.headers_mut().insert_raw(HeaderValue::new(name, value));
+        }
+    }
+    if let Some(feedback_id) = feedback_id {
+        email.headers_mut().insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("Feedback-ID"), feedback_id));
+    }
+    if let Some(auto_submitted) = auto_submitted {
+        email.headers_mut().insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("Auto-Submitted"), auto_submitted));
+    }
+    if let Some(precedence) = precedence {
+        email.headers_mut().insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("Precedence"), precedence));
+    }
+    if let Some(mailer_name) = mailer_name {
+        email.headers_mut().insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("X-Mailer"), mailer_name.clone()));
+    }
+    if cli.diagnostic_headers {
+        // Only metadata of the request, never its headers, so the api key can't leak
+        let request_id = request_id
+            .filter(|id| !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)))
+            .unwrap_or_else(|| format!("{:016x}", fastrand::u64(..)));
+        let diagnostics = [
+            ("X-Received-From-Ip", remote_addr.map(|addr| addr.ip().to_string()).unwrap_or_else(|| String::from("unknown"))),
+            ("X-Received-Request-Id", request_id),
+            ("X-Received-At", httpdate::fmt_http_date(SystemTime::now())),
+        ];
+        for (name, value) in diagnostics {
+            email.headers_mut().insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str(name), value));
+        }
+    }
+
+    // Check recipients against the blocklists
+    for recipient in email.envelope().to() {
+        let is_role = cli.block_role_addresses && ROLE_ADDRESSES.iter().any(|role| recipient.user().eq_ignore_ascii_case(role));
+        if is_role || state.disposable_domains.contains(&recipient.domain().to_lowercase()) {
+            return Err(Error::BlockedRecipient(recipient.to_string()));
+        }
+        if state.suppressed.contains(&recipient.to_string().to_lowercase()) {
+            return Err(Error::SuppressedRecipient(recipient.to_string()));
+        }
+    }
+    // Check the size once encoded, rather than having the relay reject it
+    if email.formatted().len() as u64 > cli.max_message_size {
+        return Err(Error::MessageTooLarge(cli.max_message_size));
+    }
+
+    if dry_run {
+        return Ok(Delivery { sent: 0, failed: 0, report: Some(preview(&email)), recipients: email.envelope().to().to_vec() });
+    }
+
+    // Check the global rate limit covers every copy before sending any
+    if let Some(bucket) = rate_limit {
+        let emails = match delivery_mode {
+            DeliveryMode::Shared => 1,
+            DeliveryMode::Individual => to.len(),
+        };
+        if emails > bucket.max_emails() {
+            return Err(Error::ExceedsRateLimit(emails, bucket.max_emails()));
+        }
+        bucket.check(emails).map_err(|wait| Error::RateLimited(retry_after(wait, cli)))?;
+    }
 
     // Send the message
-    let mailer = SmtpTransport::unencrypted_localhost();
-    mailer.send(&email)?;
+    let mailer = match relay {
+        Some(relay) => relay.mailer(cli.smtp_bind_address, envelope_id.as_deref()),
+        None => Mailer::new(cli.transport, &cli.sendmail_path, cli.mail_dir.as_deref(), state.smtp_credentials.as_ref(), cli.smtp_bind_address, envelope_id.as_deref()),
+    };
+    match delivery_mode {
+        DeliveryMode::Shared => {
+            // Split recipient lists too long for a single transaction
+            if let Some(size) = cli.rcpt_chunk_size.filter(|size| with_operator_copies(cli, email.envelope()).len() > size.get()) {
+                let delivery = send_in_chunks(&mailer, cli, &email, size.get())?;
+                println!("Sent an email from {from} to {} recipients in chunks of {size} ({} bytes)", email.envelope().to().len() - delivery.failed, body.len());
+                return Ok(delivery);
+            }
+            send(&mailer, cli, &email, email.envelope())?;
+            let to = to.iter().map(|to| to.to_string()).collect::<Vec<_>>().join(", ");
+            println!("Sent an email from {from} to {to} ({} bytes)", body.len());
+            Ok(Delivery { sent: 1, failed: 0, report: None, recipients: email.envelope().to().to_vec() })
+        }
+        DeliveryMode::Individual => {
+            // Send a copy to each recipient with only them in To, going on after failures
+            let mut sent = 0;
+            let mut report = String::new();
+            let mut error = None;
+            let mut failed = 0;
+            for to in &to {
+                let mut copy = email.clone();
+                copy.headers_mut().set(header::To::from(Mailboxes::from(to.clone())));
+                let envelope = Envelope::new(email.envelope().from().cloned(), vec![to.email.clone()])?;
+                match send(&mailer, cli, &copy, &envelope) {
+                    Ok(()) => {
+                        sent += 1;
+                        println!("Sent an email from {from} to {to} ({} bytes)", body.len());
+                        report.push_str(&format!("{}: sent\n", to.email));
+                    }
+                    Err(e) => {
+                        eprintln!("ERROR: Failed to send to {}: {}", to.email, e.description());
+                        report.push_str(&format!("{}: {}\n", to.email, e.description()));
+                        error = Some(e);
+                        failed += 1;
+                    }
+                }
+            }
+            match error {
+                Some(e) if sent == 0 => Err(e),
+                _ => Ok(Delivery { sent, failed, report: Some(report), recipients: email.envelope().to().to_vec() }),
+            }
+        }
+    }
+}
 
-    // Log
-    println!("Sent an email from {from} to {to} ({} bytes)", body.len());
+/// Parse the transfer encoding chosen for a part, None letting lettre pick the most compact one for its content
+fn parse_encoding(header: &'static str, value: Option<String>) -> Result<Option<ContentTransferEncoding>, Error> {
+    match value.as_deref() {
+        None | Some("auto") => Ok(None),
+        Some("7bit") => Ok(Some(ContentTransferEncoding::SevenBit)),
+        Some("8bit") => Ok(Some(ContentTransferEncoding::EightBit)),
+        Some("quoted-printable") => Ok(Some(ContentTransferEncoding::QuotedPrintable)),
+        Some("base64") => Ok(Some(ContentTransferEncoding::Base64)),
+        Some(_) => Err(Error::InvalidEncoding(header, value.unwrap_or_default())),
+    }
+}
 
-    Ok(())
+/// Encode the content of a part, failing when 7bit or 8bit can't represent it
+fn encode_body(content: &str, encoding: Option<ContentTransferEncoding>, part: &'static str) -> Result<Body, Error> {
+    match encoding {
+        None => Ok(Body::new(content.to_string())),
+        Some(encoding) => Body::new_with_encoding(content.to_string(), encoding).map_err(|_| {
+            Error::UnsuitableEncoding(part, if encoding == ContentTransferEncoding::SevenBit { "7bit" } else { "8bit" })
+        }),
+    }
+}
+
+/// Describe a message as JSON, with its headers, number of recipients and size
+fn preview(email: &Message) -> String {
+    let formatted = String::from_utf8_lossy(&email.formatted()).into_owned();
+    let header_section = formatted.split("\r\n\r\n").next().unwrap_or_default();
+
+    // Unfold header lines before splitting them into names and values
+    let mut headers: Vec<String> = Vec::new();
+    for line in header_section.split("\r\n") {
+        match headers.last_mut() {
+            Some(header) if line.starts_with([' ', '\t']) => header.push_str(line),
+            _ => headers.push(line.to_string()),
+        }
+    }
+    let headers = headers
+        .iter()
+        .filter_map(|header| header.split_once(':'))
+        .map(|(name, value)| format!("{{\"name\": {}, \"value\": {}}}", json::quote(name), json::quote(value.trim())))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("{{\"headers\": [{headers}], \"recipients\": {}, \"size\": {}}}", email.envelope().to().len(), formatted.len())
+}
+
+/// Send a message, adding operator recipients to the envelope only so they stay out of the headers
+fn send(mailer: &Mailer, cli: &Cli, email: &Message, envelope: &Envelope) -> Result<(), Error> {
+    let envelope = Envelope::new(envelope.from().cloned(), with_operator_copies(cli, envelope))?;
+    mailer.send_raw(&envelope, &email.formatted())
+}
+
+/// Send a message in one SMTP transaction per chunk of recipients, reporting the result for each of the envelope's
+///
+/// Operator recipients are spread over the chunks too, but left out of the report. Like in shared mode, the message
+/// counts as a single email sent, whatever the number of transactions.
+fn send_in_chunks(mailer: &Mailer, cli: &Cli, email: &Message, chunk_size: usize) -> Result<Delivery, Error> {
+    let envelope = email.envelope();
+    let formatted = email.formatted();
+    let mut transactions = 0;
+    let mut failed = 0;
+    let mut report = String::new();
+    let mut error = None;
+    for chunk in with_operator_copies(cli, envelope).chunks(chunk_size) {
+        let result = Envelope::new(envelope.from().cloned(), chunk.to_vec()).map_err(Error::from).and_then(|chunk_envelope| mailer.send_raw(&chunk_envelope, &formatted));
+        let recipients = chunk.iter().filter(|recipient| envelope.to().contains(recipient));
+        match result {
+            Ok(()) => {
+                transactions += 1;
+                for recipient in recipients {
+                    report.push_str(&format!("{recipient}: sent\n"));
+                }
+            }
+            Err(e) => {
+                eprintln!("ERROR: Failed to send to {} recipients: {}", chunk.len(), e.description());
+                for recipient in recipients {
+                    report.push_str(&format!("{recipient}: {}\n", e.description()));
+                    failed += 1;
+                }
+                error = Some(e);
+            }
+        }
+    }
+    match error {
+        Some(e) if transactions == 0 => Err(e),
+        _ => Ok(Delivery { sent: 1, failed, report: Some(report), recipients: envelope.to().to_vec() }),
+    }
+}
+
+/// Get the recipients of an envelope along with the archive and rule hidden copies that apply to them
+fn with_operator_copies(cli: &Cli, envelope: &Envelope) -> Vec<Address> {
+    let rule_bccs = cli.bcc_rules
+        .iter()
+        .filter(|(domain, _)| envelope.to().iter().any(|recipient| in_domains(recipient.domain(), std::slice::from_ref(domain))))
+        .map(|(_, address)| address);
+
+    let mut recipients = envelope.to().to_vec();
+    for address in cli.archive_bcc.iter().chain(rule_bccs) {
+        if !recipients.contains(address) {
+            recipients.push(address.clone());
+        }
+    }
+    recipients
+}
+
+/// List addresses the way they are written in headers
+fn join_addresses(addresses: &[Address]) -> String {
+    addresses.iter().map(Address::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Seconds a rate limited client should wait, jittered so that clients limited together don't retry together
+fn retry_after(wait: Duration, cli: &Cli) -> u64 {
+    wait.as_secs_f64().ceil() as u64 + fastrand::u64(0..=cli.retry_after_jitter)
+}
+
+/// Respond to a request, adding the configured response headers
+fn respond<R: Read>(request: Request, mut response: Response<R>, cli: &Cli) -> std::io::Result<()> {
+    for header in &cli.response_headers {
+        response.add_header(header.clone());
+    }
+    request.respond(response)
+}
+
+/// Describe the service and the endpoints enabled by the options as JSON
+fn service_info(cli: &Cli) -> String {
+    let mut endpoints = vec!["/send-email", "/validate"];
+    if cli.metrics {
+        endpoints.push("/metrics");
+    }
+    if cli.bounces_key.is_some() {
+        endpoints.push("/bounces");
+    }
+    if cli.admin_key.is_some() {
+        endpoints.extend(["/admin/reload", "/admin/suppressions/{email}"]);
+    }
+    let endpoints: Vec<String> = endpoints.iter().map(|endpoint| json::quote(&format!("{}{endpoint}", cli.path_prefix))).collect();
+    format!(r#"{{"service": "http-mailer", "version": "{}", "endpoints": [{}]}}"#, env!("CARGO_PKG_VERSION"), endpoints.join(", "))
+}
+
+/// Create the HTTP server, with a custom listen backlog if set
+fn bind_server(cli: &Cli) -> Result<Server, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(backlog) = cli.backlog else {
+        return Server::http(cli.addr.clone());
+    };
+    let addr = cli.addr.to_socket_addrs()?.next().ok_or("no address to listen on")?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Server::from_listener(TcpListener::from(socket), None)
 }
 
 fn main() {
     // Read cli arguments
     let cli = Cli::parse();
+    for (email, name) in &cli.relay_access {
+        if !cli.relays.iter().any(|(relay, _)| relay == name) {
+            Cli::command().error(ErrorKind::ValueValidation, format!("relay `{name}` allowed for {email} is not defined")).exit();
+        }
+    }
+
+    // Metrics list every sender, so they are never served without a key
+    if cli.metrics && cli.metrics_key.is_none() && cli.admin_key.is_none() {
+        Cli::command().error(ErrorKind::MissingRequiredArgument, "--metrics needs --metrics-key or --admin-key").exit();
+    }
+
+    if let (Some(warmup), Some(rate)) = (&cli.warmup, &cli.global_rate_limit) {
+        if warmup.rate.per_second() > rate.per_second() {
+            Cli::command().error(ErrorKind::ValueValidation, "the warmup rate must be lower than --global-rate-limit").exit();
+        }
+    }
+
+    let mut state = State::load(&cli).unwrap_or_else(|e| Cli::command().error(ErrorKind::Io, e).exit());
+
+    if cli.allow_no_auth {
+        eprintln!("WARNING: Authentication is disabled, anyone can send emails as anyone");
+    } else if state.api_keys.is_empty() {
+        eprintln!("WARNING: No api keys configured, every request will be rejected (use --api-key)");
+    }
+
+    if cli.verify_smtp_on_start {
+        let relays = cli.relays.iter().map(|(name, relay)| (name.as_str(), relay.mailer(cli.smtp_bind_address, None)));
+        let localhost = (cli.transport == TransportKind::Smtp).then(|| {
+            ("localhost", Mailer::new(cli.transport, &cli.sendmail_path, None, state.smtp_credentials.as_ref(), cli.smtp_bind_address, None))
+        });
+        for (name, mailer) in localhost.into_iter().chain(relays) {
+            match mailer.test_connection() {
+                Ok(true) => println!("SMTP relay {name} is reachable"),
+                Ok(false) => {
+                    eprintln!("ERROR: SMTP relay {name} did not accept the connection");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("ERROR: SMTP relay {name} is unreachable: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if let Some(mail_dir) = cli.mail_dir.as_ref().filter(|_| cli.transport == TransportKind::File) {
+        if let Err(e) = std::fs::create_dir_all(mail_dir) {
+            Cli::command().error(ErrorKind::Io, format!("failed to create {}: {e}", mail_dir.display())).exit();
+        }
+    }
+
+    if let Some(to) = &cli.self_test_to {
+        let from = cli.self_test_from.as_ref().map(Address::to_string).or_else(|| state.api_keys.first().map(|key| key.email.clone()));
+        let Some(from) = from else {
+            Cli::command().error(ErrorKind::MissingRequiredArgument, "--self-test-to needs --self-test-from when there are no api keys").exit();
+        };
+        let headers = [("From", from.as_str()), ("To", to.as_ref()), ("Subject", "http-mailer self-test")]
+            .map(|(name, value)| Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+        let body = String::from("This email was sent by http-mailer at startup to check that emails are delivered.");
+        match handle_request(&headers, body, &cli, &state, None, false, None) {
+            Ok(_) => println!("Self-test email sent to {to}"),
+            Err(e) => {
+                eprintln!("ERROR: Failed to send the self-test email to {to}: {}", e.description());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut metrics = Metrics::new(cli.metrics_max_senders);
+    let mut audit_log = cli.audit_log.as_ref().map(|path| {
+        AuditLog::open(path, cli.audit_log_max_size, cli.audit_log_keep)
+            .unwrap_or_else(|e| Cli::command().error(ErrorKind::Io, format!("failed to open {}: {e}", path.display())).exit())
+    });
+    let mut global_rate_limit = cli.global_rate_limit.map(|rate| TokenBucket::new(rate, cli.warmup));
+    let mut recent_emails: HashMap<String, (Instant, Delivery)> = HashMap::new();
 
     // Boot server
-    let server = Server::http(cli.addr.clone()).expect("Failed to launch server");
+    let server = bind_server(&cli).expect("Failed to launch server");
     println!("Listening on {}", cli.addr);
 
     // Listen for connections
     for mut request in server.incoming_requests() {
-        // Redirect root to github
-        if request.url() == "/" {
-            let location = Header::from_bytes(&b"Location"[..], &b"https://github.com/Les-Schtroumpfs/http-mailer"[..]).unwrap();
-            let _ = request.respond(Response::new_empty(StatusCode(301)).with_header(location));
+        // Strip the path prefix, leaving an empty path when it doesn't match
+        let url = request.url().to_string();
+        let path = match url.strip_prefix(&cli.path_prefix) {
+            Some("") => "/",
+            Some(path) if path.starts_with('/') => path,
+            _ => "",
+        };
+
+        // Turn away blocked clients before anything else
+        let user_agent = request.headers().iter().find(|header| header.field.equiv("user-agent")).map(|header| header.value.as_str());
+        if let Some(user_agent) = user_agent.filter(|user_agent| cli.blocked_user_agents.iter().any(|blocked| user_agent.to_lowercase().contains(&blocked.to_lowercase()))) {
+            let e = Error::BlockedUserAgent(user_agent.to_string());
+            eprintln!("ERROR: {}", e.description());
+            if let Err(e) = respond(request, Response::from(e), &cli) {
+                eprintln!("ERROR: Failed to respond {e}");
+            }
+            continue;
+        }
+
+        // Redirect root to github, or describe the service
+        if path == "/" {
+            let _ = match cli.root_mode {
+                RootMode::Redirect => {
+                    let location = Header::from_bytes(&b"Location"[..], &b"https://github.com/Les-Schtroumpfs/http-mailer"[..]).unwrap();
+                    respond(request, Response::new_empty(StatusCode(301)).with_header(location), &cli)
+                }
+                RootMode::Info => {
+                    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+                    respond(request, Response::from_string(service_info(&cli)).with_header(content_type), &cli)
+                }
+                RootMode::Empty => respond(request, Response::new_empty(StatusCode(200)), &cli),
+            };
+            continue;
+        }
+
+        // Reload api keys
+        if path == "/admin/reload" && cli.admin_key.is_some() {
+            let res = if request.method() != &Method::Post {
+                Err(Error::MethodNotAllowed)
+            } else {
+                reload(request.headers(), &cli, &mut state)
+            };
+            let res = match res {
+                Ok(summary) => respond(request, Response::from_string(summary), &cli),
+                Err(e) => {
+                    if e.status_code() != 401 {
+                        eprintln!("ERROR: {}", e.description());
+                    }
+                    respond(request, Response::from(e), &cli)
+                },
+            };
+            if let Err(e) = res {
+                eprintln!("ERROR: Failed to respond {e}");
+            }
+            continue;
+        }
+
+        // Edit the suppression list
+        if let (Some(email), Some(_)) = (path.strip_prefix("/admin/suppressions/"), &cli.admin_key) {
+            let method = request.method().clone();
+            let res = match update_suppression(request.headers(), &method, email, &cli, &mut state) {
+                Ok(summary) => respond(request, Response::from_string(summary), &cli),
+                Err(e) => {
+                    if e.status_code() != 401 {
+                        eprintln!("ERROR: {}", e.description());
+                    }
+                    respond(request, Response::from(e), &cli)
+                },
+            };
+            if let Err(e) = res {
+                eprintln!("ERROR: Failed to respond {e}");
+            }
+            continue;
+        }
+
+        // Receive bounce and complaint notifications
+        if path == "/bounces" && cli.bounces_key.is_some() {
+            let res = if request.method() != &Method::Post {
+                Err(Error::MethodNotAllowed)
+            } else {
+                check_endpoint_key(request.headers(), cli.bounces_key.as_deref())
+                    .and_then(|_| read_body(&mut request, cli.max_body_size))
+                    .and_then(|body| bounce(&body, &cli, &mut state))
+            };
+            let res = match res {
+                Ok(summary) => respond(request, Response::from_string(summary), &cli),
+                Err(e) => {
+                    if e.status_code() != 401 {
+                        eprintln!("ERROR: {}", e.description());
+                    }
+                    respond(request, Response::from(e), &cli)
+                },
+            };
+            if let Err(e) = res {
+                eprintln!("ERROR: Failed to respond {e}");
+            }
+            continue;
+        }
+
+        // Serve metrics
+        if path == "/metrics" && cli.metrics {
+            let res = match check_endpoint_key(request.headers(), cli.metrics_key.as_deref().or(cli.admin_key.as_deref())) {
+                Ok(()) => respond(request, Response::from_string(metrics.render(global_rate_limit.as_mut().map(TokenBucket::tokens))), &cli),
+                Err(e) => respond(request, Response::from(e), &cli),
+            };
+            if let Err(e) = res {
+                eprintln!("ERROR: Failed to respond {e}");
+            }
+            continue;
+        }
+
+        // Check path, /validate building emails without sending them
+        let dry_run = path == "/validate";
+        if path != "/send-email" && !dry_run {
+            let _ = respond(request, Response::new_empty(StatusCode(404)).with_data(Cursor::new("This is an http mailer server"), Some(29)), &cli);
             continue;
         }
 
-        // Check path
-        if request.url() != "/send-email" {
-            let _ = request.respond(Response::new_empty(StatusCode(404)).with_data(Cursor::new("This is an http mailer server"), Some(29)));
+        // Check api key before reading the body
+        let sender = match authenticate(request.headers(), &cli, &state, &mut metrics) {
+            Ok(sender) => sender,
+            Err(e) => {
+                if e.status_code() != 401 {
+                    eprintln!("ERROR: {}", e.description());
+                }
+                if let Err(e) = respond(request, Response::from(e), &cli) {
+                    eprintln!("ERROR: Failed to respond {e}");
+                }
+                continue;
+            }
+        };
+
+        // Record every authenticated request, with the recipients from the To header until the envelope is known
+        let to = header_value(request.headers(), "To").unwrap_or_default().to_string();
+        let mut audit = |to: &str, size: usize, status: u16| {
+            if let Some(audit_log) = audit_log.as_mut().filter(|_| !dry_run) {
+                if let Err(e) = audit_log.record(&AuditEntry { sender: &sender, to, size, status }) {
+                    eprintln!("ERROR: Failed to write the audit log: {e}");
+                }
+            }
+        };
+
+        // Check the global rate limit before reading the body, tokens being taken once emails are sent
+        if let Some(Err(wait)) = global_rate_limit.as_mut().filter(|_| !dry_run).map(|bucket| bucket.check(1)) {
+            metrics.global_rate_limited();
+            audit(&to, request.body_length().unwrap_or(0), 429);
+            let _ = respond(request, Response::from(Error::RateLimited(retry_after(wait, &cli))), &cli);
             continue;
         }
 
         // Read body
-        let mut body = String::new();
-        match request.as_reader().read_to_string(&mut body) {
-            Ok(_) => (),
-            Err(_) => {
-                let _ = request.respond(Response::new_empty(StatusCode(400)).with_data(Cursor::new("Failed to read request body"), Some(27)));
+        let body = match read_body(&mut request, cli.max_body_size) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("ERROR: {}", e.description());
+                audit(&to, request.body_length().unwrap_or(0), e.status_code());
+                let _ = respond(request, Response::from(e), &cli);
                 continue;
             },
+        };
+
+        // Answer duplicates of a recent email as it was answered, without sending it again
+        let dedupe_key = cli.dedupe_window.filter(|_| !dry_run).map(|window| {
+            recent_emails.retain(|_, (sent_at, _)| sent_at.elapsed() < Duration::from_secs(window));
+            dedupe_key(request.headers(), &body)
+        });
+        if let Some((_, delivery)) = dedupe_key.as_ref().and_then(|key| recent_emails.get(key)) {
+            println!("Skipped a duplicate email from {sender}");
+            audit(&join_addresses(&delivery.recipients), body.len(), delivery.status(&cli));
+            let res = match &delivery.report {
+                Some(report) => respond(request, Response::from_string(report.clone()).with_status_code(delivery.status(&cli)), &cli),
+                None => respond(request, Response::new_empty(StatusCode(delivery.status(&cli))), &cli),
+            };
+            if let Err(e) = res {
+                eprintln!("ERROR: Failed to respond {e}");
+            }
+            continue;
         }
 
         // Handle requests
-        let res = match handle_request(request.headers(), body, &cli.kv) {
-            Ok(_) => request.respond(Response::new_empty(StatusCode(200))),
+        let size = body.len();
+        let result = handle_request(request.headers(), body, &cli, &state, request.remote_addr(), dry_run, global_rate_limit.as_mut());
+        match &result {
+            Ok(delivery) => audit(&join_addresses(&delivery.recipients), size, delivery.status(&cli)),
+            Err(e) => audit(&to, size, e.status_code()),
+        }
+        let res = match result {
+            Ok(delivery) if dry_run => {
+                let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+                respond(request, Response::from_string(delivery.report.unwrap_or_default()).with_header(content_type), &cli)
+            },
+            Ok(delivery) => {
+                for _ in 0..delivery.sent {
+                    metrics.email_sent(&sender);
+                }
+                if let Some(bucket) = &mut global_rate_limit {
+                    bucket.take(delivery.sent);
+                }
+                // A retry must still reach the recipients the email couldn't be sent to
+                if let Some(key) = dedupe_key.filter(|_| delivery.failed == 0) {
+                    recent_emails.insert(key, (Instant::now(), delivery.clone()));
+                }
+                let status = delivery.status(&cli);
+                match delivery.report {
+                    Some(report) => respond(request, Response::from_string(report).with_status_code(status), &cli),
+                    None => respond(request, Response::new_empty(StatusCode(status)), &cli),
+                }
+            },
             Err(e) => {
+                if let Error::RateLimited(_) = e {
+                    metrics.global_rate_limited();
+                }
                 if e.status_code() != 401 {
                     eprintln!("ERROR: {}", e.description());
                 }
-                request.respond(e.into())
+                respond(request, Response::from(e), &cli)
             },
         };
         if let Err(e) = res {
@@ -164,3 +1600,445 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sha256 of "password"
+    const PASSWORD_HASH: &str = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<Header> {
+        pairs.iter().map(|(name, value)| Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()).collect()
+    }
+
+    /// Start an SMTP server accepting every email, returning its port and the commands it received
+    fn smtp_sink() -> (u16, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = commands.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                let _ = writer.write_all(b"220 sink ESMTP\r\n");
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let command = line.trim_end().to_string();
+                    let reply: &[u8] = match command.to_ascii_uppercase().as_str() {
+                        upper if upper.starts_with("EHLO") => b"250-sink\r\n250-DSN\r\n250 8BITMIME\r\n",
+                        "DATA" => {
+                            let _ = writer.write_all(b"354 go\r\n");
+                            while reader.read_line(&mut line).unwrap_or(0) > 0 && !line.ends_with("\r\n.\r\n") {}
+                            b"250 ok\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        _ => b"250 ok\r\n",
+                    };
+                    received.lock().unwrap().push(command);
+                    let _ = writer.write_all(reply);
+                    line.clear();
+                }
+            }
+        });
+        (port, commands)
+    }
+
+    /// Build the email of a request without sending it, returning its preview
+    fn preview_request(pairs: &[(&str, &str)], body: &str, cli: &Cli, state: &State) -> Result<String, Error> {
+        handle_request(&headers(pairs), body.to_string(), cli, state, None, true, None).map(|delivery| delivery.report.unwrap_or_default())
+    }
+
+    fn mailboxes(value: &str) -> Vec<Mailbox> {
+        parse_mailboxes("To", value).unwrap_or_else(|e| panic!("{}", e.description()))
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["http-mailer"].iter().chain(args))
+    }
+
+    fn state() -> State {
+        State {
+            api_keys: vec![ApiKey::from((String::from("alice@example.org"), String::from(PASSWORD_HASH)))],
+            disposable_domains: HashSet::new(),
+            smtp_credentials: None,
+            suppressed: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn repeated_from_is_rejected_before_authentication() {
+        let headers = headers(&[("Api-Key", "password"), ("From", "alice@example.org"), ("From", "CEO <ceo@example.org>")]);
+        let result = authenticate(&headers, &cli(&[]), &state(), &mut Metrics::new(10));
+        assert!(matches!(result, Err(Error::RepeatedHeader("From"))));
+    }
+
+    #[test]
+    fn duplicates_are_recognized_by_the_recipients_used() {
+        let base = [("From", "alice@example.org"), ("To", "a@example.org"), ("Subject", "s")];
+        let key = dedupe_key(&headers(&base), "body");
+        assert_eq!(key, dedupe_key(&headers(&[("Subject", "s"), ("To", "a@example.org"), ("From", "alice@example.org")]), "body"));
+        assert_ne!(key, dedupe_key(&headers(&[("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", "s")]), "body"));
+        assert_ne!(key, dedupe_key(&headers(&[base[0], base[1], base[2], ("X-Envelope-To", "b@example.org")]), "body"));
+        assert_ne!(key, dedupe_key(&headers(&base), "other body"));
+        assert_eq!(key, dedupe_key(&headers(&[base[0], base[1], base[2], ("Api-Key", "password"), ("User-Agent", "curl")]), "body"));
+    }
+
+    #[test]
+    fn requests_sent_another_way_are_not_duplicates() {
+        let base = [("From", "alice@example.org"), ("To", "a@example.org"), ("Subject", "s")];
+        let key = dedupe_key(&headers(&base), "body");
+        for header in [("X-Relay", "backup"), ("Reply-To", "b@example.org"), ("X-Delivery-Mode", "individual"), ("X-Envelope-Id", "req"), ("Organization", "Acme")] {
+            assert_ne!(key, dedupe_key(&headers(&[base[0], base[1], base[2], header]), "body"), "{}", header.0);
+        }
+        let relay = |name| dedupe_key(&headers(&[base[0], base[1], base[2], ("X-Relay", name)]), "body");
+        assert_ne!(relay("main"), relay("backup"));
+    }
+
+    #[test]
+    fn partial_deliveries_are_not_reported_as_successes() {
+        let cli = cli(&["--success-status", "202"]);
+        assert_eq!(Delivery { sent: 2, failed: 0, report: None, recipients: Vec::new() }.status(&cli), 202);
+        assert_eq!(Delivery { sent: 1, failed: 1, report: None, recipients: Vec::new() }.status(&cli), 207);
+    }
+
+    #[test]
+    fn individual_copies_are_all_checked_against_the_rate_limit() {
+        let headers = headers(&[
+            ("From", "alice@example.org"),
+            ("To", "a@example.org, b@example.org, c@example.org"),
+            ("Subject", "s"),
+            ("X-Delivery-Mode", "individual"),
+        ]);
+        let mut bucket = TokenBucket::new(parse_rate("2/min").unwrap(), None);
+        let result = handle_request(&headers, String::from("body"), &cli(&[]), &state(), None, false, Some(&mut bucket));
+        assert!(matches!(result, Err(Error::ExceedsRateLimit(3, 2))));
+
+        let mut bucket = TokenBucket::new(parse_rate("5/min").unwrap(), None);
+        bucket.take(3);
+        let result = handle_request(&headers, String::from("body"), &cli(&["--retry-after-jitter", "0"]), &state(), None, false, Some(&mut bucket));
+        assert!(matches!(result, Err(Error::RateLimited(12))));
+    }
+
+    #[test]
+    fn framing_response_headers_are_rejected() {
+        assert!(parse_response_header("X-Frame-Options=DENY").is_ok());
+        for name in ["connection", "Transfer-Encoding", "TRAILER", "Upgrade", "Content-Length", "content-type"] {
+            assert!(parse_response_header(&format!("{name}=x")).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn repeated_recipients_are_rejected_whatever_their_case() {
+        let headers = headers(&[("From", "alice@example.org"), ("To", "a@example.org"), ("to", "b@example.org"), ("Subject", "s")]);
+        let result = handle_request(&headers, String::from("body"), &cli(&[]), &state(), None, true, None);
+        assert!(matches!(result, Err(Error::RepeatedHeader("To"))));
+    }
+
+    #[test]
+    fn api_keys_are_read_from_api_key_or_bearer_authorization() {
+        assert_eq!(api_key(&headers(&[("Api-Key", "secret")])), Some("secret"));
+        assert_eq!(api_key(&headers(&[("Authorization", "Bearer  secret ")])), Some("secret"));
+        assert_eq!(api_key(&headers(&[("Authorization", "bearer secret")])), Some("secret"));
+        assert_eq!(api_key(&headers(&[("Authorization", "Bearer other"), ("Api-Key", "secret")])), Some("secret"));
+        assert_eq!(api_key(&headers(&[("Authorization", "Basic c2VjcmV0")])), None);
+        assert_eq!(api_key(&headers(&[])), None);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_strings() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(PASSWORD_HASH.as_bytes(), PASSWORD_HASH.as_bytes()));
+        assert!(!constant_time_eq(PASSWORD_HASH.as_bytes(), DUMMY_HASH.as_bytes()));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+
+    #[test]
+    fn unknown_senders_are_unauthorized() {
+        let mut metrics = Metrics::new(10);
+        let result = authenticate(&headers(&[("Api-Key", "password"), ("From", "mallory@example.org")]), &cli(&[]), &state(), &mut metrics);
+        assert!(matches!(result, Err(Error::Unauthorized(hash)) if hash == PASSWORD_HASH));
+        let result = authenticate(&headers(&[("Api-Key", "wrong"), ("From", "Alice <Alice@example.org>")]), &cli(&[]), &state(), &mut metrics);
+        assert!(matches!(result, Err(Error::Unauthorized(_))));
+        let result = authenticate(&headers(&[("Api-Key", "password"), ("From", "Alice <Alice@example.org>")]), &cli(&[]), &state(), &mut metrics);
+        assert_eq!(result.ok().as_deref(), Some("alice@example.org"));
+
+        let rendered = metrics.render(None);
+        assert!(rendered.contains("auth_failure_total{reason=\"unknown_sender\"} 1"));
+        assert!(rendered.contains("auth_failure_total{reason=\"hash_mismatch\"} 1"));
+        assert!(rendered.contains("auth_success_total 1"));
+
+        // Endpoints without a key compare against the dummy hash too, which no key matches
+        assert!(matches!(check_endpoint_key(&headers(&[("Api-Key", "password")]), None), Err(Error::Unauthorized(_))));
+    }
+
+    #[test]
+    fn senders_are_identified_by_their_lowercased_address() {
+        assert_eq!(sender_address("a@x.com"), "a@x.com");
+        assert_eq!(sender_address(" <a@x.com> "), "a@x.com");
+        assert_eq!(sender_address("Name <A@X.com>"), "a@x.com");
+        assert_eq!(sender_address("Not An Address"), "not an address");
+    }
+
+    #[test]
+    fn recipients_are_kept_in_the_first_list_they_appear_in() {
+        let mut to = mailboxes("a@example.org, b@example.org, B@Example.ORG, b@EXAMPLE.org");
+        let mut cc = mailboxes("A <a@example.org>, c@example.org");
+        let mut bcc = mailboxes("b@example.org, d@example.org");
+        dedupe_recipients([&mut to, &mut cc, &mut bcc]);
+        let addresses = |list: &[Mailbox]| list.iter().map(|mailbox| mailbox.email.to_string()).collect::<Vec<_>>();
+        // Local parts are compared exactly, so `B@Example.ORG` is another recipient
+        assert_eq!(addresses(&to), ["a@example.org", "b@example.org", "B@Example.ORG"]);
+        assert_eq!(addresses(&cc), ["c@example.org"]);
+        assert_eq!(addresses(&bcc), ["d@example.org"]);
+    }
+
+    #[test]
+    fn local_parts_are_only_lowercased_when_asked() {
+        let normalized = |case: LocalPartCase| {
+            let mut to = mailboxes("John.Doe@Example.ORG");
+            normalize_recipients([&mut to], case).unwrap_or_else(|e| panic!("{}", e.description()));
+            to[0].email.to_string()
+        };
+        assert_eq!(normalized(LocalPartCase::Preserve), "John.Doe@example.org");
+        assert_eq!(normalized(LocalPartCase::Lowercase), "john.doe@example.org");
+    }
+
+    #[test]
+    fn reloads_leaving_no_keys_keep_the_previous_ones() {
+        let empty = State { api_keys: Vec::new(), ..state() };
+        assert!(matches!(check_reloaded_state(&state(), &empty, &cli(&[])), Err(Error::ReloadFailed(_))));
+        assert!(check_reloaded_state(&state(), &empty, &cli(&["--allow-empty-reload"])).is_ok());
+        assert!(check_reloaded_state(&empty, &empty, &cli(&[])).is_ok());
+        assert!(check_reloaded_state(&empty, &state(), &cli(&[])).is_ok());
+    }
+
+    #[test]
+    fn display_names_are_checked_against_the_sender_one() {
+        let state = State {
+            api_keys: vec![ApiKey { display_name: Some(String::from("Alice Liddell")), ..ApiKey::from((String::from("alice@example.org"), String::from(PASSWORD_HASH))) }],
+            ..state()
+        };
+        let preview = |from: &str, cli: &Cli| preview_request(&[("From", from), ("To", "b@example.org"), ("Subject", "s")], "body", cli, &state);
+        assert!(preview("Alice Liddell <alice@example.org>", &cli(&[])).is_ok());
+        assert!(matches!(preview("CEO <alice@example.org>", &cli(&[])), Err(Error::DisplayNameMismatch(_))));
+        assert!(matches!(preview("alice@example.org", &cli(&[])), Err(Error::DisplayNameMismatch(_))));
+        let rewritten = preview("CEO <alice@example.org>", &cli(&["--display-name-mismatch", "rewrite"])).unwrap_or_default();
+        assert!(rewritten.contains(r#""\"Alice Liddell\" <alice@example.org>""#) && !rewritten.contains("CEO"), "{rewritten}");
+    }
+
+    #[test]
+    fn content_kinds_can_be_restricted() {
+        let text = [("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", "s")];
+        let html = [text[0], text[1], text[2], ("X-Body-Format", "html")];
+        let multipart = "text\n-----END-TEXT-BEGIN-HTML-----\n<p>html</p>";
+        let calendar = "text\n-----BEGIN-CALENDAR-----\nBEGIN:VCALENDAR\nMETHOD:REQUEST\nEND:VCALENDAR\n";
+        let kind = |result: Result<String, Error>| match result {
+            Ok(_) => None,
+            Err(Error::DisallowedContent(kind)) => Some(kind),
+            Err(e) => panic!("{}", e.description()),
+        };
+
+        let cli_text = cli(&["--allowed-content", "text"]);
+        assert_eq!(kind(preview_request(&text, "text", &cli_text, &state())), None);
+        assert_eq!(kind(preview_request(&text, multipart, &cli_text, &state())), Some("HTML"));
+        assert_eq!(kind(preview_request(&html, "<p>html</p>", &cli_text, &state())), Some("HTML"));
+        assert_eq!(kind(preview_request(&text, calendar, &cli_text, &state())), Some("calendar"));
+
+        let cli_html = cli(&["--allowed-content", "html"]);
+        assert_eq!(kind(preview_request(&html, "<p>html</p>", &cli_html, &state())), None);
+        assert_eq!(kind(preview_request(&text, "text", &cli_html, &state())), Some("text"));
+
+        let cli_all = cli(&["--allowed-content", "text", "--allowed-content", "html", "--allowed-content", "calendar"]);
+        assert_eq!(kind(preview_request(&text, multipart, &cli_all, &state())), None);
+        assert_eq!(kind(preview_request(&text, calendar, &cli_all, &state())), None);
+    }
+
+    #[test]
+    fn bcc_rules_only_copy_emails_to_their_domains() {
+        let cli = cli(&["--bcc-rule", "Example.org=audit@corp.test", "--bcc-rule", "other.test=other@corp.test", "--archive-bcc", "archive@corp.test"]);
+        let address = |address: &str| address.parse::<Address>().unwrap();
+        let recipients = |to: &[&str]| {
+            let envelope = Envelope::new(None, to.iter().map(|to| address(to)).collect()).unwrap();
+            with_operator_copies(&cli, &envelope)
+        };
+        assert_eq!(recipients(&["a@example.org"]), [address("a@example.org"), address("archive@corp.test"), address("audit@corp.test")]);
+        assert_eq!(recipients(&["a@mail.EXAMPLE.org"]), [address("a@mail.EXAMPLE.org"), address("archive@corp.test"), address("audit@corp.test")]);
+        assert_eq!(recipients(&["a@notexample.org"]), [address("a@notexample.org"), address("archive@corp.test")]);
+        // Recipients already in the envelope don't get a second copy
+        assert_eq!(recipients(&["audit@corp.test", "a@example.org"]), [address("audit@corp.test"), address("a@example.org"), address("archive@corp.test")]);
+    }
+
+    #[test]
+    fn enormous_headers_are_rejected() {
+        let subject = "a".repeat(16 * 1024 + 1);
+        let request = [("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", subject.as_str())];
+        assert!(matches!(preview_request(&request, "body", &cli(&[]), &state()), Err(Error::HeaderTooLong("Subject", 16384))));
+        assert!(preview_request(&request, "body", &cli(&["--max-header-length", "20000"]), &state()).is_ok());
+        let request = [request[0], request[1], ("Subject", &subject[..16 * 1024])];
+        assert!(preview_request(&request, "body", &cli(&[]), &state()).is_ok());
+    }
+
+    #[test]
+    fn empty_bodies_can_be_rejected() {
+        let request = [("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", "s")];
+        let rejecting = cli(&["--reject-empty-body"]);
+        assert!(matches!(preview_request(&request, "", &rejecting, &state()), Err(Error::EmptyBody)));
+        assert!(matches!(preview_request(&request, " \r\n\t\n", &rejecting, &state()), Err(Error::EmptyBody)));
+        assert!(matches!(preview_request(&request, " \n-----END-TEXT-BEGIN-HTML-----\n\n", &rejecting, &state()), Err(Error::EmptyBody)));
+        // An invitation is content enough, even without any text
+        let calendar = "\n-----BEGIN-CALENDAR-----\nBEGIN:VCALENDAR\nMETHOD:REQUEST\nEND:VCALENDAR\n";
+        assert!(preview_request(&request, calendar, &rejecting, &state()).is_ok());
+        assert!(preview_request(&request, "", &cli(&[]), &state()).is_ok());
+    }
+
+    #[test]
+    fn oversized_chunked_bodies_are_rejected() {
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        // Send 256 bytes in chunks, so the request has no Content-Length to check up front
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let request = |max_body_size: u64| {
+            let mut client = TcpStream::connect(server.server_addr().to_ip().unwrap()).unwrap();
+            let chunk = "a".repeat(64);
+            let mut raw = String::from("POST /send-email HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n");
+            for _ in 0..4 {
+                raw.push_str(&format!("{:x}\r\n{chunk}\r\n", chunk.len()));
+            }
+            raw.push_str("0\r\n\r\n");
+            client.write_all(raw.as_bytes()).unwrap();
+
+            let mut request = server.recv().unwrap();
+            assert!(request.body_length().is_none());
+            read_body(&mut request, max_body_size)
+        };
+        assert!(matches!(request(200), Err(Error::BodyTooLarge(200))));
+        assert_eq!(request(256).ok().map(|body| body.len()), Some(256));
+    }
+
+    #[test]
+    fn sender_defaults_beat_global_flags() {
+        let key = (String::from("alice@example.org"), String::from(PASSWORD_HASH));
+        let headers = vec![(String::from("Precedence"), String::from("list")), (String::from("X-Mailer"), String::from("Alice's mailer"))];
+        let state = State { api_keys: vec![ApiKey { headers, ..ApiKey::from(key) }], ..state() };
+        let cli = cli(&["--precedence", "bulk", "--mailer-name", "http-mailer"]);
+        let request = [("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", "s")];
+        let preview = preview_request(&request, "body", &cli, &state).unwrap_or_default();
+        assert!(preview.contains(r#"{"name": "Precedence", "value": "list"}"#), "{preview}");
+        assert!(preview.contains(r#"{"name": "X-Mailer", "value": "Alice's mailer"}"#), "{preview}");
+
+        // The request still beats both
+        let request = [request[0], request[1], request[2], ("Precedence", "junk")];
+        let preview = preview_request(&request, "body", &cli, &state).unwrap_or_default();
+        assert!(preview.contains(r#"{"name": "Precedence", "value": "junk"}"#), "{preview}");
+    }
+
+    #[test]
+    fn aliases_are_authenticated_as_themselves_and_shown_as_canonical() {
+        let key = (String::from("alice@example.org"), String::from(PASSWORD_HASH));
+        let state = State { api_keys: vec![ApiKey { canonical_from: Some("team@example.org".parse().unwrap()), ..ApiKey::from(key) }], ..state() };
+        let auth = |from: &str| authenticate(&headers(&[("Api-Key", "password"), ("From", from)]), &cli(&[]), &state, &mut Metrics::new(10));
+        assert_eq!(auth("Alice <alice@example.org>").ok().as_deref(), Some("alice@example.org"));
+        assert!(matches!(auth("team@example.org"), Err(Error::Unauthorized(_))));
+
+        let (port, commands) = smtp_sink();
+        let request = [("From", "Alice <alice@example.org>"), ("To", "b@example.org"), ("Subject", "s"), ("X-Relay", "sink")];
+        let relay = format!("sink=127.0.0.1:{port}");
+        let envelope_from = |mode: &str| {
+            let cli = cli(&["--relay", &relay, "--relay-access", "alice@example.org=sink", "--canonical-from-envelope", mode]);
+            let preview = preview_request(&request, "body", &cli, &state).unwrap_or_default();
+            assert!(preview.contains(r#""Alice <team@example.org>""#), "{preview}");
+            commands.lock().unwrap().clear();
+            handle_request(&headers(&request), String::from("body"), &cli, &state, None, false, None).unwrap_or_else(|e| panic!("{}", e.description()));
+            commands.lock().unwrap().iter().find(|command| command.starts_with("MAIL FROM:")).cloned().unwrap_or_default()
+        };
+        assert!(envelope_from("original").starts_with("MAIL FROM:<alice@example.org>"));
+        assert!(envelope_from("canonical").starts_with("MAIL FROM:<team@example.org>"));
+    }
+
+    #[test]
+    fn simulated_rate_limits_tell_when_to_retry() {
+        let retry_after = |e: Error| {
+            let response = Response::from(e);
+            response.headers().iter().find(|header| header.field.equiv("retry-after")).map(|header| header.value.to_string())
+        };
+        assert_eq!(retry_after(Error::Simulated(TestFailure::RateLimited)).as_deref(), Some("1"));
+        assert_eq!(retry_after(Error::RateLimited(12)).as_deref(), Some("12"));
+        assert_eq!(retry_after(Error::Simulated(TestFailure::Smtp)), None);
+    }
+
+    #[test]
+    fn envelope_ids_are_passed_to_the_relay() {
+        let (port, commands) = smtp_sink();
+        let relay = format!("sink=127.0.0.1:{port}");
+        let relay_cli = cli(&["--relay", &relay, "--relay-access", "alice@example.org=sink"]);
+        let request = |envelope_id: &str| headers(&[("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", "s"), ("X-Relay", "sink"), ("X-Envelope-Id", envelope_id)]);
+        handle_request(&request("req+1=x"), String::from("body"), &relay_cli, &state(), None, false, None).unwrap_or_else(|e| panic!("{}", e.description()));
+        assert!(commands.lock().unwrap().contains(&String::from("MAIL FROM:<alice@example.org> ENVID=req+2B1+3Dx")), "{:?}", commands.lock().unwrap());
+
+        for envelope_id in ["with space", "tab\tid", &"a".repeat(101)] {
+            let result = handle_request(&request(envelope_id), String::from("body"), &relay_cli, &state(), None, true, None);
+            assert!(matches!(result, Err(Error::InvalidEnvelopeId(_))), "{envelope_id}");
+        }
+        let request = headers(&[("From", "alice@example.org"), ("To", "b@example.org"), ("Subject", "s"), ("X-Envelope-Id", "req")]);
+        let result = handle_request(&request, String::from("body"), &cli(&["--transport", "sendmail"]), &state(), None, true, None);
+        assert!(matches!(result, Err(Error::EnvelopeIdWithoutSmtp)));
+    }
+
+    #[test]
+    fn body_read_failures_are_told_apart() {
+        use std::io::Write;
+        use std::net::{Shutdown, TcpStream};
+
+        // Connections inherit the read timeout of the listener they are accepted from
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        socket.listen(8).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let listener = TcpListener::from(socket);
+        let addr = listener.local_addr().unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let status = |head: &str, body: &str, close: bool| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(format!("POST /send-email HTTP/1.1\r\nHost: localhost\r\n{head}\r\n\r\n{body}").as_bytes()).unwrap();
+            if close {
+                client.shutdown(Shutdown::Write).unwrap();
+            }
+            let mut request = server.recv().unwrap();
+            read_body(&mut request, 4096).err().map(|e| e.status_code())
+        };
+        // A chunked body cut short by the client closing the connection, before its last chunk
+        assert_eq!(status("Transfer-Encoding: chunked", "5\r\nhello\r\n", true), Some(400));
+        // A chunk size that isn't hexadecimal
+        assert_eq!(status("Transfer-Encoding: chunked", "zz\r\nabc\r\n0\r\n\r\n", false), Some(400));
+        // A body that stops arriving before its announced length, large enough for tiny_http not to read it upfront
+        assert_eq!(status("Content-Length: 2000", "only a few bytes", false), Some(408));
+    }
+
+    #[test]
+    fn requests_beyond_the_warmup_capacity_are_rejected() {
+        let headers = headers(&[
+            ("From", "alice@example.org"),
+            ("To", "a@example.org, b@example.org, c@example.org"),
+            ("Subject", "s"),
+            ("X-Delivery-Mode", "individual"),
+        ]);
+        let mut bucket = TokenBucket::new(parse_rate("10/min").unwrap(), Some(parse_warmup("2/min:1d").unwrap()));
+        assert_eq!(bucket.max_emails(), 2);
+        let result = handle_request(&headers, String::from("body"), &cli(&[]), &state(), None, false, Some(&mut bucket));
+        assert!(matches!(result, Err(Error::ExceedsRateLimit(3, 2))));
+    }
+
+    #[test]
+    fn success_statuses_must_carry_a_body() {
+        assert_eq!(parse_success_status("202"), Ok(202));
+        for status in ["204", "205", "199", "300", "abc"] {
+            assert!(parse_success_status(status).is_err(), "{status}");
+        }
+    }
+}
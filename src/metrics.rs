@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Label used for senders beyond the cardinality cap
+const OTHER_SENDER: &str = "other";
+
+/// Why a request failed authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AuthFailure {
+    MissingKey,
+    UnknownSender,
+    HashMismatch,
+}
+
+impl AuthFailure {
+    fn label(self) -> &'static str {
+        match self {
+            AuthFailure::MissingKey => "missing_key",
+            AuthFailure::UnknownSender => "unknown_sender",
+            AuthFailure::HashMismatch => "hash_mismatch",
+        }
+    }
+}
+
+/// Counters exposed in the Prometheus text format on /metrics
+pub struct Metrics {
+    max_sender_labels: usize,
+    emails_sent: HashMap<String, u64>,
+    global_rate_limited: u64,
+    auth_success: u64,
+    auth_failure: HashMap<AuthFailure, u64>,
+}
+
+impl Metrics {
+    pub fn new(max_sender_labels: usize) -> Metrics {
+        Metrics { max_sender_labels, emails_sent: HashMap::new(), global_rate_limited: 0, auth_success: 0, auth_failure: HashMap::new() }
+    }
+
+    pub fn email_sent(&mut self, sender: &str) {
+        let distinct_senders = self.emails_sent.keys().filter(|label| *label != OTHER_SENDER).count();
+        let label = match self.emails_sent.contains_key(sender) || distinct_senders < self.max_sender_labels {
+            true => sender,
+            false => OTHER_SENDER,
+        };
+        *self.emails_sent.entry(label.to_string()).or_default() += 1;
+    }
+
+    pub fn global_rate_limited(&mut self) {
+        self.global_rate_limited += 1;
+    }
+
+    pub fn auth_success(&mut self) {
+        self.auth_success += 1;
+    }
+
+    pub fn auth_failure(&mut self, reason: AuthFailure) {
+        *self.auth_failure.entry(reason).or_default() += 1;
+    }
+
+    /// Render the counters, along with the tokens left in the global rate limit if there is one
+    pub fn render(&self, global_rate_limit_tokens: Option<f64>) -> String {
+        let mut output = String::new();
+        output.push_str("# HELP emails_sent_total Emails sent, by sender\n");
+        output.push_str("# TYPE emails_sent_total counter\n");
+        let mut emails_sent: Vec<_> = self.emails_sent.iter().collect();
+        emails_sent.sort();
+        for (sender, count) in emails_sent {
+            let _ = writeln!(output, "emails_sent_total{{sender=\"{}\"}} {count}", escape_label(sender));
+        }
+        output.push_str("# HELP auth_success_total Requests authenticated with a valid api key\n");
+        output.push_str("# TYPE auth_success_total counter\n");
+        let _ = writeln!(output, "auth_success_total {}", self.auth_success);
+        output.push_str("# HELP auth_failure_total Requests rejected for a missing or wrong api key, by reason\n");
+        output.push_str("# TYPE auth_failure_total counter\n");
+        let mut auth_failure: Vec<_> = self.auth_failure.iter().collect();
+        auth_failure.sort();
+        for (reason, count) in auth_failure {
+            let _ = writeln!(output, "auth_failure_total{{reason=\"{}\"}} {count}", reason.label());
+        }
+        if let Some(tokens) = global_rate_limit_tokens {
+            output.push_str("# HELP global_rate_limit_tokens Emails that can be sent right now under the global rate limit\n");
+            output.push_str("# TYPE global_rate_limit_tokens gauge\n");
+            let _ = writeln!(output, "global_rate_limit_tokens {}", tokens.max(0.0).floor());
+            output.push_str("# HELP global_rate_limited_total Requests rejected by the global rate limit\n");
+            output.push_str("# TYPE global_rate_limited_total counter\n");
+            let _ = writeln!(output, "global_rate_limited_total {}", self.global_rate_limited);
+        }
+        output
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
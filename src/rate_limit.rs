@@ -0,0 +1,120 @@
+//! Token bucket limiting how many emails are sent over time
+
+use std::time::{Duration, Instant};
+
+/// A number of emails per period, such as `500/min`
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub count: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub fn per_second(&self) -> f64 {
+        self.count as f64 / self.per.as_secs_f64()
+    }
+}
+
+/// Rate at which sending starts, growing linearly to the full rate over the period
+#[derive(Debug, Clone, Copy)]
+pub struct Warmup {
+    pub rate: Rate,
+    pub period: Duration,
+}
+
+pub fn parse_rate(s: &str) -> Result<Rate, String> {
+    let (count, unit) = s.split_once('/').ok_or_else(|| format!("invalid rate `{s}`: expected COUNT/UNIT such as 500/min"))?;
+    let count = count.trim().parse::<u32>().ok().filter(|count| *count > 0).ok_or_else(|| format!("invalid count `{count}`"))?;
+    Ok(Rate { count, per: Duration::from_secs(parse_unit(unit)?) })
+}
+
+/// Parse a warmup such as `10/h:7d`, starting at 10 emails per hour and reaching the full rate after 7 days
+pub fn parse_warmup(s: &str) -> Result<Warmup, String> {
+    let (rate, period) = s.split_once(':').ok_or_else(|| format!("invalid warmup `{s}`: expected COUNT/UNIT:PERIOD such as 10/h:7d"))?;
+    let period = period.trim();
+    let idx = period.find(|c: char| !c.is_ascii_digit()).unwrap_or(period.len());
+    let length = period[..idx].parse::<u64>().ok().filter(|length| *length > 0).ok_or_else(|| format!("invalid period `{period}`"))?;
+    Ok(Warmup { rate: parse_rate(rate)?, period: Duration::from_secs(length * parse_unit(&period[idx..])?) })
+}
+
+/// Get the length of a unit in seconds
+fn parse_unit(unit: &str) -> Result<u64, String> {
+    match unit.trim() {
+        "s" | "sec" | "second" => Ok(1),
+        "min" | "minute" => Ok(60),
+        "h" | "hour" => Ok(3600),
+        "d" | "day" => Ok(86400),
+        unit => Err(format!("unknown unit `{unit}`: expected s, min, h or d")),
+    }
+}
+
+/// Bucket holding up to `count` tokens, refilled continuously at the rate
+///
+/// During a warmup, both the refill rate and the capacity grow from the warmup rate, the capacity never going below one token.
+pub struct TokenBucket {
+    rate: Rate,
+    warmup: Option<Warmup>,
+    started: Instant,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, warmup: Option<Warmup>) -> TokenBucket {
+        let now = Instant::now();
+        let mut bucket = TokenBucket { rate, warmup, started: now, tokens: 0.0, updated: now };
+        bucket.tokens = bucket.capacity(bucket.per_second(now));
+        bucket
+    }
+
+    /// Emails per second currently allowed
+    fn per_second(&self, now: Instant) -> f64 {
+        match self.warmup {
+            Some(warmup) => {
+                let progress = (now.duration_since(self.started).as_secs_f64() / warmup.period.as_secs_f64()).min(1.0);
+                warmup.rate.per_second() + (self.rate.per_second() - warmup.rate.per_second()) * progress
+            }
+            None => self.rate.per_second(),
+        }
+    }
+
+    fn capacity(&self, per_second: f64) -> f64 {
+        (per_second * self.rate.per.as_secs_f64()).max(1.0)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let per_second = self.per_second(now);
+        let refilled = now.duration_since(self.updated).as_secs_f64() * per_second;
+        self.tokens = (self.tokens + refilled).min(self.capacity(per_second));
+        self.updated = now;
+    }
+
+    /// Check that some emails can be sent, returning how long to wait otherwise
+    ///
+    /// The caller must make sure they fit in the bucket, or it would wait for tokens that never come.
+    pub fn check(&mut self, emails: usize) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= emails as f64 {
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((emails as f64 - self.tokens) / self.per_second(self.updated)))
+    }
+
+    /// Most emails that can be sent at once right now, when the bucket is full, which grows during a warmup
+    pub fn max_emails(&self) -> usize {
+        self.capacity(self.per_second(Instant::now())).floor() as usize
+    }
+
+    /// Take a token per sent email, going below zero when a request sent several
+    pub fn take(&mut self, emails: usize) {
+        self.refill();
+        self.tokens -= emails as f64;
+    }
+
+    /// Tokens currently available
+    pub fn tokens(&mut self) -> f64 {
+        self.refill();
+        self.tokens
+    }
+}
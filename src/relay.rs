@@ -0,0 +1,91 @@
+use lettre::transport::smtp::authentication::Credentials;
+use std::net::IpAddr;
+use lettre::SmtpTransport;
+use crate::transport::{DirectSmtp, Mailer};
+
+/// A named SMTP relay selected with the X-Relay header
+///
+/// Connections are unencrypted, just like the default localhost relay.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Relay {
+    /// Build the transport to this relay, connecting from `bind_address` and sending `envelope_id` if set
+    pub fn mailer(&self, bind_address: Option<IpAddr>, envelope_id: Option<&str>) -> Mailer {
+        match (bind_address, envelope_id) {
+            (None, None) => Mailer::Smtp(self.transport()),
+            (local_address, envelope_id) => Mailer::DirectSmtp(DirectSmtp {
+                host: self.host.clone(),
+                port: self.port,
+                credentials: self.credentials.clone(),
+                local_address,
+                envelope_id: envelope_id.map(str::to_string),
+            }),
+        }
+    }
+
+    fn transport(&self) -> SmtpTransport {
+        let mut builder = SmtpTransport::builder_dangerous(&self.host).port(self.port);
+        if let Some((user, password)) = &self.credentials {
+            builder = builder.credentials(Credentials::new(user.clone(), password.clone()));
+        }
+        builder.build()
+    }
+}
+
+/// Parse a relay in the form NAME=[USER:PASSWORD@]HOST[:PORT], IPv6 hosts being written in brackets
+pub fn parse_relay(s: &str) -> Result<(String, Relay), String> {
+    let pos = s.find('=').ok_or_else(|| format!("invalid NAME=RELAY: no `=` found in `{s}`"))?;
+    let name = s[..pos].trim().to_lowercase();
+    if name.is_empty() {
+        return Err(String::from("invalid name: relay name is empty"));
+    }
+    let relay = s[pos + 1..].trim();
+    let (credentials, server) = match relay.rfind('@') {
+        Some(idx) => {
+            let (user, password) = relay[..idx].split_once(':').ok_or_else(|| format!("invalid credentials: no `:` found in `{}`", &relay[..idx]))?;
+            (Some((user.to_string(), password.to_string())), &relay[idx + 1..])
+        }
+        None => (None, relay),
+    };
+    let (host, port) = match server.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(|| format!("invalid relay: no `]` found in `{server}`"))?;
+            match rest.strip_prefix(':') {
+                Some(port) => (host, port),
+                None if rest.is_empty() => (host, "25"),
+                None => return Err(format!("invalid relay: expected `:PORT` after `]` in `{server}`")),
+            }
+        }
+        None if server.matches(':').count() > 1 => {
+            return Err(format!("invalid relay: IPv6 addresses must be written in brackets, such as `[{server}]`"));
+        }
+        None => server.split_once(':').unwrap_or((server, "25")),
+    };
+    let port = port.parse::<u16>().map_err(|e| format!("invalid port `{port}`: {e}"))?;
+    if host.is_empty() {
+        return Err(format!("invalid relay: no host found in `{relay}`"));
+    }
+    Ok((name, Relay { host: host.to_string(), port, credentials }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_relays_are_written_in_brackets() {
+        let relay = |s: &str| parse_relay(s).map(|(_, relay)| (relay.host, relay.port));
+        assert_eq!(relay("main=smtp.example.org"), Ok((String::from("smtp.example.org"), 25)));
+        assert_eq!(relay("main=user:secret@smtp.example.org:587"), Ok((String::from("smtp.example.org"), 587)));
+        assert_eq!(relay("main=[fe80::1]"), Ok((String::from("fe80::1"), 25)));
+        assert_eq!(relay("main=[fe80::1]:2525"), Ok((String::from("fe80::1"), 2525)));
+        for invalid in ["main=fe80::1", "main=[fe80::1", "main=[fe80::1]2525", "main=smtp.example.org:port"] {
+            assert!(relay(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::{Credentials, DEFAULT_MECHANISMS};
+use lettre::transport::smtp::client::SmtpConnection;
+use lettre::transport::smtp::commands::{Data, Mail, Rcpt};
+use lettre::transport::smtp::extension::{ClientId, MailBodyParameter, MailParameter};
+use lettre::{SendmailTransport, SmtpTransport, Transport};
+use crate::error::Error;
+
+/// How emails are delivered when no relay is selected
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// Local SMTP server at port 25
+    Smtp,
+    /// Local sendmail binary
+    Sendmail,
+    /// `.eml` files written to a directory instead of being sent
+    File,
+}
+
+/// A transport able to deliver an already formatted email
+pub enum Mailer {
+    Smtp(SmtpTransport),
+    DirectSmtp(DirectSmtp),
+    Sendmail(SendmailTransport),
+    File(PathBuf),
+}
+
+/// SMTP server reached over a connection of our own, for what `SmtpTransport` has no option for: a local address
+/// to connect from, or a DSN envelope ID
+///
+/// It behaves like an unencrypted `SmtpTransport` with lettre's default timeout and authentication mechanisms.
+pub struct DirectSmtp {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+    pub local_address: Option<IpAddr>,
+    /// `ENVID` parameter of MAIL FROM (RFC 3461), sent as is and xtext-encoded by lettre
+    pub envelope_id: Option<String>,
+}
+
+impl DirectSmtp {
+    fn connect(&self) -> Result<SmtpConnection, lettre::transport::smtp::Error> {
+        let server = (self.host.as_str(), self.port);
+        let mut connection = SmtpConnection::connect(server, Some(Duration::from_secs(60)), &ClientId::default(), None, self.local_address)?;
+        if let Some((user, password)) = &self.credentials {
+            connection.auth(DEFAULT_MECHANISMS, &Credentials::new(user.clone(), password.clone()))?;
+        }
+        Ok(connection)
+    }
+
+    pub fn test_connection(&self) -> Result<bool, lettre::transport::smtp::Error> {
+        let mut connection = self.connect()?;
+        let connected = connection.test_connected();
+        let _ = connection.quit();
+        Ok(connected)
+    }
+
+    fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<(), lettre::transport::smtp::Error> {
+        let mut connection = self.connect()?;
+        match &self.envelope_id {
+            Some(envelope_id) => send_with_envelope_id(&mut connection, envelope, email, envelope_id)?,
+            None => {
+                connection.send(envelope, email)?;
+            }
+        }
+        let _ = connection.quit();
+        Ok(())
+    }
+}
+
+/// Send an email like `SmtpConnection::send`, which can't add parameters to MAIL FROM, with an ENVID parameter
+///
+/// Unlike it, SMTPUTF8 and 8BITMIME are requested without checking the server supports them, leaving the relay to refuse.
+fn send_with_envelope_id(connection: &mut SmtpConnection, envelope: &Envelope, email: &[u8], envelope_id: &str) -> Result<(), lettre::transport::smtp::Error> {
+    let mut parameters = vec![MailParameter::Other { keyword: String::from("ENVID"), value: Some(envelope_id.to_string()) }];
+    if envelope.from().into_iter().chain(envelope.to()).any(|address| !address.user().is_ascii() || !address.domain().is_ascii()) {
+        parameters.push(MailParameter::SmtpUtfEight);
+    }
+    if !email.is_ascii() {
+        parameters.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+    }
+    let result = (|| {
+        connection.command(Mail::new(envelope.from().cloned(), parameters))?;
+        for recipient in envelope.to() {
+            connection.command(Rcpt::new(recipient.clone(), Vec::new()))?;
+        }
+        connection.command(Data)?;
+        connection.message(email)
+    })();
+    if result.is_err() {
+        connection.abort();
+    }
+    result.map(|_| ())
+}
+
+/// Build the transport to the local SMTP server at port 25
+pub fn localhost(credentials: Option<&(String, String)>) -> SmtpTransport {
+    match credentials {
+        Some((user, password)) => SmtpTransport::builder_dangerous("localhost")
+            .credentials(Credentials::new(user.clone(), password.clone()))
+            .build(),
+        None => SmtpTransport::unencrypted_localhost(),
+    }
+}
+
+/// Load SMTP credentials from a file containing `user=USER` and `password=PASSWORD` lines
+///
+/// Files readable by anyone are refused, and files readable by their group trigger a warning.
+pub fn load_credentials_file(path: &Path) -> Result<(String, String), String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?.permissions().mode();
+        if mode & 0o004 != 0 {
+            return Err(format!("{} is readable by anyone, restrict its permissions (e.g. chmod 600)", path.display()));
+        }
+        if mode & 0o040 != 0 {
+            eprintln!("WARNING: {} is readable by its group", path.display());
+        }
+    }
+
+    let content = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let mut user = None;
+    let mut password = None;
+    for line in content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        match line.split_once('=') {
+            Some(("user", value)) => user = Some(value.to_string()),
+            Some(("password", value)) => password = Some(value.to_string()),
+            _ => return Err(format!("{}: invalid line, expected user=USER or password=PASSWORD", path.display())),
+        }
+    }
+    match (user, password) {
+        (Some(user), Some(password)) => Ok((user, password)),
+        _ => Err(format!("{}: both user and password are required", path.display())),
+    }
+}
+
+impl Mailer {
+    /// Build the default transport, the envelope ID being only sent by SMTP ones
+    pub fn new(kind: TransportKind, sendmail_path: &Path, mail_dir: Option<&Path>, credentials: Option<&(String, String)>, bind_address: Option<IpAddr>, envelope_id: Option<&str>) -> Mailer {
+        match (kind, bind_address, envelope_id) {
+            (TransportKind::Smtp, None, None) => Mailer::Smtp(localhost(credentials)),
+            (TransportKind::Smtp, local_address, envelope_id) => Mailer::DirectSmtp(DirectSmtp {
+                host: String::from("localhost"),
+                port: 25,
+                credentials: credentials.cloned(),
+                local_address,
+                envelope_id: envelope_id.map(str::to_string),
+            }),
+            (TransportKind::Sendmail, ..) => Mailer::Sendmail(SendmailTransport::new_with_command(sendmail_path)),
+            (TransportKind::File, ..) => Mailer::File(mail_dir.unwrap_or(Path::new(".")).to_path_buf()),
+        }
+    }
+
+    /// Check that an SMTP server accepts connections, other transports always being ready
+    pub fn test_connection(&self) -> Result<bool, lettre::transport::smtp::Error> {
+        match self {
+            Mailer::Smtp(mailer) => mailer.test_connection(),
+            Mailer::DirectSmtp(mailer) => mailer.test_connection(),
+            Mailer::Sendmail(_) | Mailer::File(_) => Ok(true),
+        }
+    }
+
+    pub fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<(), Error> {
+        match self {
+            Mailer::Smtp(mailer) => {
+                mailer.send_raw(envelope, email)?;
+            }
+            Mailer::DirectSmtp(mailer) => {
+                mailer.send_raw(envelope, email)?;
+            }
+            Mailer::Sendmail(mailer) => {
+                mailer.send_raw(envelope, email)?;
+            }
+            Mailer::File(mail_dir) => {
+                let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                let path = mail_dir.join(format!("{millis}-{:016x}.eml", fastrand::u64(..)));
+                let mut file = OpenOptions::new().write(true).create_new(true).open(&path).map_err(Error::FileError)?;
+                file.write_all(email).map_err(Error::FileError)?;
+            }
+        }
+        Ok(())
+    }
+}